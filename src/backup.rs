@@ -0,0 +1,376 @@
+// Backups of every key Switchroom stores, read at a single version so that
+// the copy is consistent, in the `BackupFrame` format described in the proto
+// file. Restoring replays the keys into an empty store of any backend, so a
+// backup of an Fdb cluster can seed an embedded store and the other way round.
+// Fdb only serves reads at versions from the last few seconds, so a backup
+// which takes longer than that fails straight away with `VersionTooOld`
+// rather than producing an inconsistent copy. Clusters too large to read in
+// that time need Fdb's own `fdbbackup` instead.
+use crate::storage::{MessageStore, StorageError};
+
+use data_encoding::HEXLOWER;
+use futures::Future;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use switchroom_grpc::proto;
+use switchroom_grpc::proto::backup_frame::Frame;
+
+// Keys per chunk, and per transaction when reading or restoring
+const BATCH_SIZE: usize = 1000;
+// Fdb's limits on key and value sizes, which keys from every backend are
+// within, since they can all be restored into Fdb
+const MAX_KEY_SIZE: u64 = 10_000;
+const MAX_VALUE_SIZE: u64 = 100_000;
+// A chunk of the largest keys and values, with room for the encoding's
+// overhead. Any longer frame length was corrupted.
+const MAX_FRAME_SIZE: u64 = BATCH_SIZE as u64 * (MAX_KEY_SIZE + MAX_VALUE_SIZE + 32) + 1024;
+
+#[derive(Debug, Fail)]
+pub enum BackupError {
+    #[fail(display = "Storage error: {:?}", err)]
+    StorageError { err: String },
+    #[fail(display = "IO error: {:?}", err)]
+    IoError { err: String },
+    #[fail(display = "Backup is corrupt: {}", err)]
+    Corrupt { err: String },
+    #[fail(display = "refusing to restore into a store which isn't empty")]
+    NotEmpty,
+}
+
+impl From<StorageError> for BackupError {
+    fn from(err: StorageError) -> BackupError {
+        BackupError::StorageError {
+            err: err.to_string(),
+        }
+    }
+}
+
+impl From<std::io::Error> for BackupError {
+    fn from(err: std::io::Error) -> BackupError {
+        BackupError::IoError {
+            err: err.to_string(),
+        }
+    }
+}
+
+impl From<prost::EncodeError> for BackupError {
+    fn from(err: prost::EncodeError) -> BackupError {
+        BackupError::IoError {
+            err: err.to_string(),
+        }
+    }
+}
+
+impl From<prost::DecodeError> for BackupError {
+    fn from(err: prost::DecodeError) -> BackupError {
+        corrupt(err.to_string())
+    }
+}
+
+fn corrupt(err: String) -> BackupError {
+    BackupError::Corrupt { err }
+}
+
+/// What a backup holds, or what was written or restored.
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub read_version: i64,
+    pub key_count: usize,
+    /// The backup's overall checksum, in hex.
+    pub checksum: String,
+}
+
+fn hash_entry(hasher: &mut Sha256, key: &[u8], value: &[u8]) {
+    hasher.input(&(key.len() as u64).to_be_bytes());
+    hasher.input(key);
+    hasher.input(&(value.len() as u64).to_be_bytes());
+    hasher.input(value);
+}
+
+fn write_frame<W: Write>(writer: &mut W, frame: Frame) -> Result<(), BackupError> {
+    use prost::Message;
+
+    let frame = proto::BackupFrame { frame: Some(frame) };
+    let mut buf = Vec::with_capacity(frame.encoded_len() + 10);
+    frame.encode_length_delimited(&mut buf)?;
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+/// Read the next frame, or `None` at the end of the file.
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Frame>, BackupError> {
+    use prost::Message;
+
+    // The varint length prefix
+    let mut length: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8];
+        if reader.read(&mut byte)? == 0 {
+            if shift == 0 {
+                return Ok(None);
+            }
+            return Err(corrupt("truncated frame length".to_string()));
+        }
+        length |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 63 {
+            return Err(corrupt("invalid frame length".to_string()));
+        }
+    }
+
+    if length > MAX_FRAME_SIZE {
+        return Err(corrupt(format!(
+            "frame length {} is over the limit of {}",
+            length, MAX_FRAME_SIZE
+        )));
+    }
+
+    // Read through `take`, so that a truncated file doesn't allocate the
+    // whole length up front
+    let mut buf = vec![];
+    reader.by_ref().take(length).read_to_end(&mut buf)?;
+    if buf.len() as u64 != length {
+        return Err(corrupt("truncated frame".to_string()));
+    }
+    match proto::BackupFrame::decode(&buf)?.frame {
+        Some(frame) => Ok(Some(frame)),
+        None => Err(corrupt("empty frame".to_string())),
+    }
+}
+
+/// Write every key in `storage` to `writer`.
+pub fn backup<W: Write>(
+    storage: &dyn MessageStore,
+    writer: &mut W,
+) -> Result<Summary, BackupError> {
+    use crate::messages::Timestamped;
+
+    let mut hasher = Sha256::new();
+    let mut key_count = 0;
+    let mut version = None;
+    let mut cursor = None;
+    loop {
+        let batch = storage.backup_keys(version, cursor, BATCH_SIZE).wait()?;
+        if version.is_none() {
+            let header = proto::BackupHeader {
+                read_version: batch.version,
+                created_at: None,
+            }
+            .timestamped();
+            write_frame(writer, Frame::Header(header))?;
+            version = Some(batch.version);
+        }
+
+        let mut chunk_hasher = Sha256::new();
+        let mut entries = Vec::with_capacity(batch.kvs.len());
+        for (key, value) in batch.kvs {
+            hash_entry(&mut chunk_hasher, &key, &value);
+            hash_entry(&mut hasher, &key, &value);
+            entries.push(proto::BackupEntry { key, value });
+        }
+        key_count += entries.len();
+        if !entries.is_empty() {
+            let chunk = proto::BackupChunk {
+                entries,
+                checksum: chunk_hasher.result().to_vec(),
+            };
+            write_frame(writer, Frame::Chunk(chunk))?;
+        }
+        info!("Backed up {} keys", key_count);
+
+        cursor = batch.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    let checksum = hasher.result().to_vec();
+    write_frame(
+        writer,
+        Frame::Trailer(proto::BackupTrailer {
+            key_count: key_count as i64,
+            checksum: checksum.clone(),
+        }),
+    )?;
+    writer.flush()?;
+
+    Ok(Summary {
+        read_version: version.unwrap_or_default(),
+        key_count,
+        checksum: HEXLOWER.encode(&checksum),
+    })
+}
+
+/// Check every checksum in the backup read from `reader`, passing each chunk
+/// to `f` once it's been verified.
+fn read_backup<R: Read>(
+    reader: &mut R,
+    f: &mut dyn FnMut(Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), BackupError>,
+) -> Result<Summary, BackupError> {
+    let read_version = match read_frame(reader)? {
+        Some(Frame::Header(header)) => header.read_version,
+        _ => return Err(corrupt("missing header".to_string())),
+    };
+
+    let mut hasher = Sha256::new();
+    let mut key_count = 0;
+    loop {
+        match read_frame(reader)? {
+            Some(Frame::Chunk(chunk)) => {
+                let mut chunk_hasher = Sha256::new();
+                let mut kvs = Vec::with_capacity(chunk.entries.len());
+                for entry in chunk.entries {
+                    hash_entry(&mut chunk_hasher, &entry.key, &entry.value);
+                    hash_entry(&mut hasher, &entry.key, &entry.value);
+                    kvs.push((entry.key, entry.value));
+                }
+                if chunk_hasher.result().as_slice() != &chunk.checksum[..] {
+                    return Err(corrupt(format!(
+                        "checksum mismatch in the chunk after key {}",
+                        key_count
+                    )));
+                }
+                key_count += kvs.len();
+                f(kvs)?;
+            }
+            Some(Frame::Trailer(trailer)) => {
+                let checksum = hasher.result().to_vec();
+                if trailer.key_count as usize != key_count || trailer.checksum != checksum {
+                    return Err(corrupt("trailer doesn't match the chunks".to_string()));
+                }
+                if read_frame(reader)?.is_some() {
+                    return Err(corrupt("data after the trailer".to_string()));
+                }
+                return Ok(Summary {
+                    read_version,
+                    key_count,
+                    checksum: HEXLOWER.encode(&checksum),
+                });
+            }
+            Some(Frame::Header(_)) => return Err(corrupt("repeated header".to_string())),
+            None => return Err(corrupt("missing trailer".to_string())),
+        }
+    }
+}
+
+/// Check the backup read from `reader` without restoring anything.
+pub fn verify<R: Read>(reader: &mut R) -> Result<Summary, BackupError> {
+    read_backup(reader, &mut |_kvs| Ok(()))
+}
+
+/// Replay the backup read from `reader` into `storage`, which must be empty.
+/// Chunks are written as they're verified, so check the backup with `verify`
+/// first to avoid a partial restore.
+pub fn restore<R: Read>(
+    storage: &dyn MessageStore,
+    reader: &mut R,
+) -> Result<Summary, BackupError> {
+    if !storage.is_empty().wait()? {
+        return Err(BackupError::NotEmpty);
+    }
+
+    let mut restored = 0;
+    read_backup(reader, &mut |kvs| {
+        restored += kvs.len();
+        storage.restore_keys(kvs).wait()?;
+        info!("Restored {} keys", restored);
+        Ok(())
+    })
+}
+
+/// Back up `storage` to the file at `path`.
+pub fn backup_to_file(storage: &dyn MessageStore, path: &str) -> Result<Summary, BackupError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    backup(storage, &mut writer)
+}
+
+/// Verify the backup at `path`, then unless `dry_run` is set, restore it into
+/// `storage`.
+pub fn restore_from_file(
+    storage: &dyn MessageStore,
+    path: &str,
+    dry_run: bool,
+) -> Result<Summary, BackupError> {
+    let summary = verify(&mut BufReader::new(File::open(path)?))?;
+    if dry_run {
+        return Ok(summary);
+    }
+    restore(storage, &mut BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::messages::Timestamped;
+    use crate::storage::memory::MemoryDB;
+    use crate::storage::testing;
+
+    fn make_message(hash: &str, from: &str, to: &str) -> proto::Message {
+        testing::make_message(hash, from, to, vec![0u8; 25_000]).timestamped()
+    }
+
+    fn all_keys(storage: &dyn MessageStore) -> Vec<(Vec<u8>, Vec<u8>)> {
+        storage
+            .backup_keys(None, None, 1_000_000)
+            .wait()
+            .unwrap()
+            .kvs
+    }
+
+    #[test]
+    fn backup_and_restore_test() {
+        let source = MemoryDB::new(30);
+        for n in 0..600 {
+            let message = make_message(&format!("hash {}", n), "alice", "bob");
+            source.insert_message(message).wait().unwrap();
+        }
+
+        let mut buf = vec![];
+        let summary = backup(&source, &mut buf).unwrap();
+        assert_eq!(summary.key_count, all_keys(&source).len());
+        // Read over several transactions
+        assert_eq!(summary.key_count > BATCH_SIZE, true);
+        assert_eq!(verify(&mut &buf[..]).unwrap(), summary);
+
+        let target = MemoryDB::new(30);
+        assert_eq!(restore(&target, &mut &buf[..]).unwrap(), summary);
+        assert_eq!(all_keys(&target), all_keys(&source));
+
+        // Only an empty store can be restored into
+        match restore(&target, &mut &buf[..]) {
+            Err(BackupError::NotEmpty) => (),
+            other => panic!("expected NotEmpty, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn corrupt_backup_test() {
+        let source = MemoryDB::new(30);
+        source
+            .insert_message(make_message("hash", "alice", "bob"))
+            .wait()
+            .unwrap();
+        let mut buf = vec![];
+        backup(&source, &mut buf).unwrap();
+
+        // A flipped bit in a value, and a truncated file
+        let mut flipped = buf.clone();
+        let middle = flipped.len() / 2;
+        flipped[middle] ^= 1;
+        assert_eq!(verify(&mut &flipped[..]).is_err(), true);
+        assert_eq!(verify(&mut &buf[..buf.len() - 10]).is_err(), true);
+
+        // A length prefix of nearly 2^63, with no frame after it
+        let huge = [0xffu8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        match verify(&mut &huge[..]) {
+            Err(BackupError::Corrupt { .. }) => (),
+            other => panic!("expected Corrupt, got {:?}", other),
+        }
+    }
+}
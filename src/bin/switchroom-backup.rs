@@ -0,0 +1,35 @@
+extern crate env_logger;
+extern crate switchroom;
+
+use switchroom::backup;
+use switchroom::{config, storage};
+
+const USAGE: &str = "usage: switchroom-backup <output path>";
+
+/// Writes every key in the configured store to a file, all read at a single
+/// version, with checksums for each chunk of keys and for the whole backup.
+/// With Fdb, the whole backup has to be read within Fdb's few seconds of
+/// version history, and fails otherwise.
+pub fn main() {
+    use std::env;
+
+    ::env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 1 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let path = &args[0];
+
+    config::load_config();
+
+    let storage = storage::open(&config::CONFIG).expect("Unable to open storage");
+
+    let summary = backup::backup_to_file(&*storage, path).expect("Unable to write backup");
+
+    println!(
+        "Backed up {} keys at version {} to {}, checksum {}",
+        summary.key_count, summary.read_version, path, summary.checksum
+    );
+}
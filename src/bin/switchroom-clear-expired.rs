@@ -1,9 +1,17 @@
 extern crate env_logger;
+extern crate futures;
+#[macro_use]
+extern crate log;
 extern crate switchroom;
 
-use std::sync::Arc;
-use switchroom::{config, storage};
+use futures::Future;
+use std::collections::BTreeMap;
+use switchroom::{config, metrics, storage};
 
+/// Clears expired messages in batches of `expiry_batch_size`, each in its own
+/// transaction. A failure keeps the batches already committed, so it's safe
+/// to run again. Messages of clients under legal hold are kept, and reported
+/// at the end.
 pub fn main() {
     use std::env;
 
@@ -16,7 +24,36 @@ pub fn main() {
         instrumented::init(&config::CONFIG.metrics.bind_to_address);
     }
 
-    let storage = Arc::new(storage::DB::new(config::CONFIG.message_expiry_days));
+    let storage = storage::open(&config::CONFIG).expect("Unable to open storage");
 
-    storage.clear_expired();
+    info!("Looking for expired keys");
+
+    let mut total = 0;
+    let mut held = BTreeMap::new();
+    let mut cursor = None;
+    loop {
+        let (result, next) = storage
+            .clear_expired(cursor, config::CONFIG.storage.expiry_batch_size)
+            .wait()
+            .expect("Unable to clear expired messages");
+        total += result.cleared;
+        metrics::EXPIRED_MESSAGE.inc_by(result.cleared as i64);
+        for (client_id, count) in result.held {
+            metrics::HELD_EXPIRED_MESSAGE.inc_by(count as i64);
+            *held.entry(client_id).or_insert(0) += count;
+        }
+        info!("Cleared {} expired keys", total);
+
+        cursor = next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    for (client_id, count) in &held {
+        info!(
+            "Kept {} expired keys for {}, which is under legal hold",
+            count, client_id
+        );
+    }
 }
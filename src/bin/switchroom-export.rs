@@ -0,0 +1,49 @@
+extern crate env_logger;
+extern crate switchroom;
+
+use switchroom::export::{self, Format};
+use switchroom::{config, storage};
+
+const USAGE: &str =
+    "usage: switchroom-export [--json] [--include-expired] <client id> <output path>";
+
+/// Writes every message stored for a client to a file, for a data-portability
+/// request, along with a manifest of the exported hashes and counts. Messages
+/// are length-delimited protobufs unless `--json` asks for JSON lines.
+pub fn main() {
+    use std::env;
+
+    ::env_logger::init();
+
+    let mut format = Format::Protobuf;
+    let mut include_expired = false;
+    let mut args = vec![];
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => format = Format::JsonLines,
+            "--include-expired" => include_expired = true,
+            _ => args.push(arg),
+        }
+    }
+    if args.len() != 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let (client_id, path) = (&args[0], &args[1]);
+
+    config::load_config();
+
+    let storage = storage::open(&config::CONFIG).expect("Unable to open storage");
+
+    let manifest = export::export_to_file(&*storage, client_id, format, include_expired, path)
+        .expect("Unable to export messages");
+
+    println!(
+        "Exported {} messages ({} bytes) for {} to {}, manifest in {}",
+        manifest.message_count,
+        manifest.bytes,
+        client_id,
+        path,
+        export::manifest_path(path)
+    );
+}
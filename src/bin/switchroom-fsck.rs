@@ -0,0 +1,86 @@
+extern crate env_logger;
+extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate switchroom;
+
+use futures::Future;
+use std::collections::BTreeMap;
+use switchroom::config;
+use switchroom::storage::{self, ProblemKind};
+
+const USAGE: &str = "usage: switchroom-fsck [--repair]";
+
+// Keys checked per transaction
+const BATCH_SIZE: usize = 1000;
+
+/// Checks stored bodies for missing or undecodable chunks, and that mailbox
+/// entries, index entries and expiry keys all point at something. Prints each
+/// problem and a summary, then exits with status 1 if anything other than an
+/// unpaired body was left unrepaired. With `--repair`, broken bodies are
+/// removed along with the entries, index entries and expiry keys which
+/// pointed at them, and mismatched entries are rewritten. Bodies only in one
+/// participant's mailbox are reported but left alone, since a legal hold can
+/// leave them that way.
+pub fn main() {
+    use std::env;
+
+    ::env_logger::init();
+
+    let mut repair = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--repair" => repair = true,
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    config::load_config();
+
+    let storage = storage::open(&config::CONFIG).expect("Unable to open storage");
+
+    let mut checked = 0;
+    let mut found: BTreeMap<ProblemKind, (usize, usize)> = BTreeMap::new();
+    let mut cursor = None;
+    loop {
+        let (report, next) = storage
+            .check_integrity(cursor, BATCH_SIZE, repair)
+            .wait()
+            .expect("Unable to check storage");
+        checked += report.keys_checked;
+        for problem in report.problems {
+            println!(
+                "{:?}{}: {}",
+                problem.kind,
+                if problem.repaired { " (repaired)" } else { "" },
+                problem.detail
+            );
+            let counts = found.entry(problem.kind).or_insert((0, 0));
+            counts.0 += 1;
+            if problem.repaired {
+                counts.1 += 1;
+            }
+        }
+        info!("Checked {} keys", checked);
+
+        cursor = next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    println!("Checked {} keys", checked);
+    let mut unrepaired = false;
+    for (kind, (count, repaired)) in &found {
+        println!("{:?}: {} found, {} repaired", kind, count, repaired);
+        if *kind != ProblemKind::UnpairedEntry && repaired < count {
+            unrepaired = true;
+        }
+    }
+    if unrepaired {
+        std::process::exit(1);
+    }
+}
@@ -31,8 +31,8 @@ pub enum Error {
     BadArgs,
     #[fail(display = "client is not serving")]
     NotServing,
-    #[fail(display = "bad response")]
-    BadResponse,
+    #[fail(display = "bad response: {}", err)]
+    BadResponse { err: String },
 }
 
 impl From<url::ParseError> for Error {
@@ -90,7 +90,9 @@ pub fn main() -> Result<(), Error> {
     let result = runtime.block_on(
         make_client
             .make_service(dst)
-            .map_err(|e| panic!("connect error: {:?}", e))
+            .map_err(|e| Error::IoError {
+                err: format!("connect error: {:?}", e),
+            })
             .and_then(move |conn| {
                 use switchroom_grpc::proto::client::Switchroom;
 
@@ -100,29 +102,30 @@ pub fn main() -> Result<(), Error> {
                     .unwrap();
 
                 // Wait until the client is ready...
-                Switchroom::new(conn).ready()
+                Switchroom::new(conn).ready().map_err(|e| Error::IoError {
+                    err: format!("{:?}", e),
+                })
             })
             .and_then(|mut client| {
-                client.check(Request::new(proto::HealthCheckRequest {
-                    service: "switchroom".into(),
-                }))
+                client
+                    .check(Request::new(proto::HealthCheckRequest {
+                        service: "switchroom".into(),
+                    }))
+                    .map_err(|e| Error::BadResponse {
+                        err: format!("{:?}", e),
+                    })
             })
-            .map(|response| response.get_ref().clone())
-            .map_err(|e| {
-                error!("ERR = {:?}", e);
-                panic!("health check failed");
-            }),
+            .map(|response| response.get_ref().clone()),
     );
 
     info!("{:?}", result);
 
-    if let Ok(response) = result {
-        if response.status == proto::health_check_response::ServingStatus::Serving as i32 {
-            Ok(())
-        } else {
-            Err(Error::NotServing)
-        }
+    // The service reports itself as not serving while its storage is
+    // unavailable
+    let response = result?;
+    if response.status == proto::health_check_response::ServingStatus::Serving as i32 {
+        Ok(())
     } else {
-        Err(Error::BadResponse)
+        Err(Error::NotServing)
     }
 }
@@ -0,0 +1,22 @@
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate switchroom;
+
+use switchroom::{config, storage};
+
+/// Runs every migration the stored data hasn't had yet, in order, bringing
+/// it up to the schema version of this build. The service keeps serving
+/// older layouts in the meantime, so it can run alongside it. Safe to
+/// interrupt and run again.
+pub fn main() {
+    ::env_logger::init();
+
+    config::load_config();
+
+    let storage = storage::open(&config::CONFIG).expect("Unable to open storage");
+
+    let version =
+        storage::migrate(&*storage, storage::BATCH_SIZE).expect("Unable to migrate stored data");
+    info!("Stored data is at schema version {}", version);
+}
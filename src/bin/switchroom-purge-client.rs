@@ -0,0 +1,56 @@
+extern crate env_logger;
+extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate switchroom;
+
+use futures::Future;
+use switchroom::{config, storage};
+
+/// Erases every message to or from the client ID given as the only argument,
+/// including the copies in counterparts' mailboxes, such as when an account
+/// is deleted. Safe to interrupt and run again.
+pub fn main() {
+    use std::env;
+
+    ::env_logger::init();
+
+    let client_id = match env::args().nth(1) {
+        Some(client_id) => client_id,
+        None => {
+            eprintln!("usage: switchroom-purge-client <client id>");
+            std::process::exit(2);
+        }
+    };
+
+    config::load_config();
+
+    let storage = storage::open(&config::CONFIG).expect("Unable to open storage");
+
+    let mut total = storage::PurgedMessages::default();
+    loop {
+        let (purged, more) = storage
+            .purge_client(&client_id, storage::BATCH_SIZE)
+            .wait()
+            .expect("Unable to purge client");
+        total.messages += purged.messages;
+        total.bytes += purged.bytes;
+        total.held += purged.held;
+        info!("Purged {} messages so far", total.messages);
+
+        if !more {
+            break;
+        }
+    }
+
+    println!(
+        "Purged {} messages ({} bytes) for {}",
+        total.messages, total.bytes, client_id
+    );
+    if total.held > 0 {
+        println!(
+            "Kept {} messages in counterparts' mailboxes under legal hold",
+            total.held
+        );
+    }
+}
@@ -0,0 +1,41 @@
+extern crate env_logger;
+extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate switchroom;
+
+use futures::Future;
+use switchroom::{config, storage};
+
+/// Rewrites every stored body not encrypted with the keyring's active key,
+/// including bodies stored before encryption was turned on. Run it after
+/// rotating keys, before dropping the old key from the keyring. Safe to
+/// interrupt and run again.
+pub fn main() {
+    ::env_logger::init();
+
+    config::load_config();
+
+    if config::CONFIG.storage.keyring_path.is_none() {
+        eprintln!("No keyring_path is configured, there's nothing to encrypt with");
+        std::process::exit(1);
+    }
+
+    let storage = storage::open(&config::CONFIG).expect("Unable to open storage");
+
+    let mut cursor = None;
+    let mut total = 0;
+    loop {
+        let (count, next) = storage
+            .reencrypt_blobs(cursor, storage::BATCH_SIZE)
+            .wait()
+            .expect("Unable to re-encrypt messages");
+        total += count;
+        info!("Re-encrypted {} message bodies", total);
+
+        cursor = next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+}
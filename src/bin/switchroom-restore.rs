@@ -0,0 +1,51 @@
+extern crate env_logger;
+extern crate switchroom;
+
+use switchroom::backup;
+use switchroom::{config, storage};
+
+const USAGE: &str = "usage: switchroom-restore [--dry-run] <backup path>";
+
+/// Replays a backup written by switchroom-backup into the configured store,
+/// which must be empty. Every checksum is verified before anything is written,
+/// and `--dry-run` stops after verifying.
+pub fn main() {
+    use std::env;
+
+    ::env_logger::init();
+
+    let mut dry_run = false;
+    let mut args = vec![];
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            _ => args.push(arg),
+        }
+    }
+    if args.len() != 1 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let path = &args[0];
+
+    config::load_config();
+
+    let storage = storage::open(&config::CONFIG).expect("Unable to open storage");
+
+    let summary = match backup::restore_from_file(&*storage, path, dry_run) {
+        Ok(summary) => summary,
+        Err(err) => {
+            eprintln!("Unable to restore {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+
+    println!(
+        "{} {} keys from version {} of {}, checksum {}",
+        if dry_run { "Verified" } else { "Restored" },
+        summary.key_count,
+        summary.read_version,
+        path,
+        summary.checksum
+    );
+}
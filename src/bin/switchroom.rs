@@ -19,7 +19,6 @@ use switchroom::service;
 use switchroom::storage;
 
 use futures::{Future, Stream};
-use std::sync::Arc;
 use switchroom_grpc::proto::server;
 use tokio::net::TcpListener;
 use tower_hyper::server::{Http, Server};
@@ -36,9 +35,42 @@ pub fn main() {
         instrumented::init(&config::CONFIG.metrics.bind_to_address);
     }
 
-    let storage = Arc::new(storage::DB::new(config::CONFIG.message_expiry_days));
+    let storage = match storage::open(&config::CONFIG) {
+        Ok(storage) => storage,
+        Err(err) => {
+            error!("Unable to open storage: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Older layouts are still served while switchroom-migrate moves them
+    // forward, but a newer one may not be understood
+    let schema_version = match storage.schema_version().wait() {
+        Ok(schema_version) => schema_version,
+        Err(err) => {
+            error!("Unable to read the schema version: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if schema_version > storage::SCHEMA_VERSION {
+        error!(
+            "Stored data is at schema version {}, but this build only knows up to {}",
+            schema_version,
+            storage::SCHEMA_VERSION
+        );
+        std::process::exit(1);
+    }
+    if schema_version < storage::SCHEMA_VERSION {
+        warn!(
+            "Stored data is at schema version {}, run switchroom-migrate to bring it up to {}",
+            schema_version,
+            storage::SCHEMA_VERSION
+        );
+    }
 
-    let new_service = server::SwitchroomServer::new(service::Switchroom::new(storage.clone()));
+    let new_service = server::SwitchroomServer::new(
+        service::Switchroom::new(storage.clone()).with_limits(config::CONFIG.limits.clone()),
+    );
 
     let mut server = Server::new(new_service);
 
@@ -66,6 +98,36 @@ pub fn main() {
         .expect("Unable to build tokio runtime");
 
     rt.spawn(serve);
+
+    if let Some(admin) = &config::CONFIG.admin {
+        let mut admin_service = service::SwitchroomAdmin::new(storage.clone());
+        if let Some(dir) = &admin.export_dir {
+            admin_service = admin_service.with_export_dir(dir);
+        }
+        let new_service = server::SwitchroomAdminServer::new(admin_service);
+        let mut server = Server::new(new_service);
+        let http = Http::new().http2_only(true).clone();
+
+        let addr = admin.bind_to_address.parse().unwrap();
+        let bind = TcpListener::bind(&addr).expect("bind admin");
+
+        let serve = bind
+            .incoming()
+            .for_each(move |sock| {
+                let addr = sock.peer_addr().ok();
+                info!("New admin connection from addr={:?}", addr);
+
+                let serve = server.serve_with(sock, http.clone());
+                tokio::spawn(serve.map_err(|e| error!("hyper error: {:?}", e)));
+
+                Ok(())
+            })
+            .map_err(|e| error!("admin accept error: {}", e));
+
+        rt.spawn(serve);
+        info!("Admin endpoints listening on {}", addr);
+    }
+
     info!(
         "Started server with {} threads, listening on {}",
         config::CONFIG.service.worker_threads,
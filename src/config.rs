@@ -1,4 +1,5 @@
 use log::info;
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
@@ -9,7 +10,17 @@ use yansi::Paint;
 pub struct Config {
     pub service: Service,
     pub metrics: Metrics,
+    /// The admin endpoints are only served when set.
+    #[serde(default)]
+    pub admin: Option<Admin>,
+    /// The Fdb backend, with its defaults, when the section is left out.
+    #[serde(default)]
+    pub storage: Storage,
     pub message_expiry_days: i64,
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(default)]
+    pub limits: Limits,
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,6 +37,149 @@ pub struct Metrics {
     pub bind_to_address: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Admin {
+    pub bind_to_address: String,
+    /// Directory exports are written under. Exports are refused when unset.
+    #[serde(default)]
+    pub export_dir: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Storage {
+    #[serde(default)]
+    pub backend: StorageBackend,
+    /// Directory for the embedded backend's data files, which it requires.
+    #[serde(default)]
+    pub embedded_path: Option<String>,
+    #[serde(default)]
+    pub compression: Compression,
+    /// Keyring file for encrypting bodies at rest. Bodies are stored in the
+    /// clear when unset.
+    #[serde(default)]
+    pub keyring_path: Option<String>,
+    /// Expired entries cleared per transaction.
+    #[serde(default = "default_expiry_batch_size")]
+    pub expiry_batch_size: usize,
+    /// Cluster file for the Fdb backend. Fdb's default is used when unset.
+    #[serde(default)]
+    pub cluster_file: Option<String>,
+    /// Milliseconds before an Fdb transaction fails, including its retries.
+    #[serde(default)]
+    pub transaction_timeout_ms: Option<i32>,
+    /// Times an Fdb transaction is retried before failing.
+    #[serde(default)]
+    pub transaction_retry_limit: Option<i32>,
+    /// Longest delay between retries of an Fdb transaction, in milliseconds.
+    #[serde(default)]
+    pub transaction_max_retry_delay_ms: Option<i32>,
+    /// Path under which the Fdb backend keeps its keys, so that several
+    /// deployments can share a cluster. An empty path is the root of the
+    /// cluster, which overlaps every other path, so it's only for moving data
+    /// written before keyspaces existed.
+    #[serde(default = "default_keyspace")]
+    pub keyspace: Vec<String>,
+    /// Threads which run storage transactions, so that slow transactions
+    /// don't hold up the service's worker threads. Each one blocks for the
+    /// whole of a transaction, mostly waiting on storage rather than using a
+    /// CPU, so there should be enough for every transaction in flight.
+    /// `storage::DEFAULT_THREADS` when unset.
+    #[serde(default)]
+    pub threads: Option<usize>,
+}
+
+fn default_expiry_batch_size() -> usize {
+    1000
+}
+
+fn default_keyspace() -> Vec<String> {
+    vec!["switchroom".to_string()]
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            backend: StorageBackend::default(),
+            embedded_path: None,
+            compression: Compression::default(),
+            keyring_path: None,
+            expiry_batch_size: default_expiry_batch_size(),
+            cluster_file: None,
+            transaction_timeout_ms: None,
+            transaction_retry_limit: None,
+            transaction_max_retry_delay_ms: None,
+            keyspace: default_keyspace(),
+            threads: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Retention {
+    /// Days to keep messages for particular recipients, overriding
+    /// `message_expiry_days`.
+    #[serde(default)]
+    pub clients: HashMap<String, i64>,
+}
+
+/// Limits on sent messages, past which they're rejected.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub max_body_bytes: usize,
+    pub max_nonce_bytes: usize,
+    /// Seconds a message's `sent_at` may be ahead of the server's clock.
+    pub max_clock_skew_seconds: i64,
+    /// Longest `ttl_seconds` a message may ask for. Messages are never kept
+    /// past their retention period whatever their TTL. 0 rejects any message
+    /// that sets a TTL.
+    pub max_ttl_seconds: i64,
+    /// Most hashes a `Sync` stream holds, from acks and from its backlog,
+    /// before it fails. Each is held until the stream has read past it.
+    pub max_sync_skipped: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_body_bytes: 1_048_576,
+            max_nonce_bytes: 24,
+            max_clock_skew_seconds: 300,
+            max_ttl_seconds: 31_536_000,
+            max_sync_skipped: 5_000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Fdb,
+    Embedded,
+    Memory,
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        StorageBackend::Fdb
+    }
+}
+
+/// Codec for newly written message bodies. Bodies are readable whichever
+/// codec they were written with, so this can be changed at any time.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
 fn get_switchroom_toml_path() -> String {
     env::var("SWITCHROOM_TOML").unwrap_or_else(|_| "Switchroom.toml".to_string())
 }
@@ -53,3 +207,36 @@ pub fn load_config() {
     );
     info!("CONFIG => {:#?}", Paint::red(&*CONFIG));
 }
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    const MINIMAL_TOML: &str = r#"
+        message_expiry_days = 30
+
+        [service]
+        worker_threads = 1
+        ca_cert_path = "ca.crt"
+        tls_cert_path = "tls.crt"
+        tls_key_path = "tls.key"
+        bind_to_address = "127.0.0.1:10012"
+
+        [metrics]
+        bind_to_address = "127.0.0.1:5002"
+    "#;
+
+    #[test]
+    fn default_storage_test() {
+        // Configs from before the storage section existed use Fdb
+        let config: Config = toml::from_str(MINIMAL_TOML).unwrap();
+        match config.storage.backend {
+            StorageBackend::Fdb => (),
+            other => panic!("expected Fdb, got {:?}", other),
+        }
+        assert_eq!(config.storage.keyspace, vec!["switchroom".to_string()]);
+        assert_eq!(config.storage.expiry_batch_size, 1000);
+        assert_eq!(config.storage.embedded_path, None);
+    }
+}
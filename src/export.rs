@@ -0,0 +1,296 @@
+// Writes out everything stored for a client, for data-portability requests.
+// Messages are written either as length-delimited `proto::Message`s or as
+// JSON lines, and a JSON manifest listing the exported hashes and counts is
+// written next to them.
+use crate::storage::{MessageStore, StorageError};
+
+use chrono::prelude::*;
+use data_encoding::BASE64URL_NOPAD;
+use futures::Future;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use switchroom_grpc::proto;
+
+// Messages read per transaction
+const BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Fail)]
+pub enum ExportError {
+    #[fail(display = "Storage error: {:?}", err)]
+    StorageError { err: String },
+    #[fail(display = "IO error: {:?}", err)]
+    IoError { err: String },
+    #[fail(display = "Encoding failed: {:?}", err)]
+    EncodingFailure { err: String },
+}
+
+impl From<StorageError> for ExportError {
+    fn from(err: StorageError) -> ExportError {
+        ExportError::StorageError {
+            err: err.to_string(),
+        }
+    }
+}
+
+impl From<std::io::Error> for ExportError {
+    fn from(err: std::io::Error) -> ExportError {
+        ExportError::IoError {
+            err: err.to_string(),
+        }
+    }
+}
+
+impl From<prost::EncodeError> for ExportError {
+    fn from(err: prost::EncodeError) -> ExportError {
+        ExportError::EncodingFailure {
+            err: err.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ExportError {
+    fn from(err: serde_json::Error) -> ExportError {
+        ExportError::EncodingFailure {
+            err: err.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Each message is a varint length followed by the encoded message.
+    Protobuf,
+    /// One JSON object per line, with bytes fields in URL-safe base64.
+    JsonLines,
+}
+
+/// What an export wrote.
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub client_id: String,
+    pub format: Format,
+    pub include_expired: bool,
+    pub exported_at: String,
+    pub message_count: usize,
+    /// Messages sent and received by the client. A message the client sent
+    /// to itself counts as both.
+    pub sent_count: usize,
+    pub received_count: usize,
+    /// The encoded size of the exported messages.
+    pub bytes: usize,
+    /// Hashes of the exported messages in URL-safe base64, in the order they
+    /// were written.
+    pub hashes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    hash: String,
+    from: &'a str,
+    to: &'a str,
+    body: String,
+    received_at: Option<String>,
+    nonce: String,
+    sender_public_key: String,
+    recipient_public_key: String,
+    sent_at: Option<String>,
+    signature: String,
+    value_cents: i32,
+    ttl_seconds: i64,
+}
+
+/// `None` for timestamps which aren't set, or which are out of range. Those
+/// were never validated before they were stored, so they can be anything.
+fn to_rfc3339(timestamp: &Option<proto::Timestamp>) -> Option<String> {
+    timestamp.as_ref().and_then(|timestamp| {
+        Utc.timestamp_opt(timestamp.seconds, timestamp.nanos as u32)
+            .single()
+            .map(|time| time.to_rfc3339())
+    })
+}
+
+fn write_message<W: Write>(
+    writer: &mut W,
+    format: Format,
+    message: &proto::Message,
+) -> Result<(), ExportError> {
+    use prost::Message;
+
+    match format {
+        Format::Protobuf => {
+            let mut buf = Vec::with_capacity(message.encoded_len() + 10);
+            message.encode_length_delimited(&mut buf)?;
+            writer.write_all(&buf)?;
+        }
+        Format::JsonLines => {
+            let json = JsonMessage {
+                hash: BASE64URL_NOPAD.encode(&message.hash),
+                from: &message.from,
+                to: &message.to,
+                body: BASE64URL_NOPAD.encode(&message.body),
+                received_at: to_rfc3339(&message.received_at),
+                nonce: BASE64URL_NOPAD.encode(&message.nonce),
+                sender_public_key: BASE64URL_NOPAD.encode(&message.sender_public_key),
+                recipient_public_key: BASE64URL_NOPAD.encode(&message.recipient_public_key),
+                sent_at: to_rfc3339(&message.sent_at),
+                signature: BASE64URL_NOPAD.encode(&message.signature),
+                value_cents: message.value_cents,
+                ttl_seconds: message.ttl_seconds,
+            };
+            serde_json::to_writer(&mut *writer, &json)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// Write every message in `client_id`'s mailbox to `writer`, with no limit on
+/// the total size. Expired messages which haven't been cleared yet are
+/// included when `include_expired` is set.
+pub fn export_messages<W: Write>(
+    storage: &dyn MessageStore,
+    client_id: &str,
+    format: Format,
+    include_expired: bool,
+    writer: &mut W,
+) -> Result<Manifest, ExportError> {
+    use prost::Message;
+
+    let mut manifest = Manifest {
+        client_id: client_id.to_string(),
+        format,
+        include_expired,
+        exported_at: Utc::now().to_rfc3339(),
+        message_count: 0,
+        sent_count: 0,
+        received_count: 0,
+        bytes: 0,
+        hashes: vec![],
+    };
+
+    let mut cursor = None;
+    loop {
+        let page = storage
+            .export_messages(client_id, cursor, BATCH_SIZE, include_expired)
+            .wait()?;
+        for message in &page.messages {
+            write_message(writer, format, message)?;
+
+            manifest.message_count += 1;
+            if message.from == client_id {
+                manifest.sent_count += 1;
+            }
+            if message.to == client_id {
+                manifest.received_count += 1;
+            }
+            manifest.bytes += message.encoded_len();
+            manifest.hashes.push(BASE64URL_NOPAD.encode(&message.hash));
+        }
+
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    writer.flush()?;
+
+    Ok(manifest)
+}
+
+/// The path of the manifest written alongside an export to `path`.
+pub fn manifest_path(path: &str) -> String {
+    format!("{}.manifest.json", path)
+}
+
+/// Export `client_id`'s mailbox to the file at `path`, and its manifest to
+/// `manifest_path(path)`.
+pub fn export_to_file(
+    storage: &dyn MessageStore,
+    client_id: &str,
+    format: Format,
+    include_expired: bool,
+    path: &str,
+) -> Result<Manifest, ExportError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let manifest = export_messages(storage, client_id, format, include_expired, &mut writer)?;
+
+    let mut writer = BufWriter::new(File::create(manifest_path(path))?);
+    serde_json::to_writer_pretty(&mut writer, &manifest)?;
+    writer.flush()?;
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::messages::Timestamped;
+    use crate::storage::memory::MemoryDB;
+    use crate::storage::testing;
+
+    fn make_message(hash: &str, from: &str, to: &str) -> proto::Message {
+        testing::make_message(hash, from, to, "yoyoyoyo".into()).timestamped()
+    }
+
+    #[test]
+    fn export_test() {
+        use prost::Message;
+
+        let db = MemoryDB::new(30);
+        let messages = vec![
+            make_message("hash 1", "alice", "bob"),
+            make_message("hash 2", "bob", "alice"),
+            make_message("hash 3", "bob", "bob"),
+        ];
+        for message in &messages {
+            db.insert_message(message.clone()).wait().unwrap();
+        }
+
+        let mut buf = vec![];
+        let manifest = export_messages(&db, "bob", Format::Protobuf, false, &mut buf).unwrap();
+        assert_eq!(manifest.message_count, 3);
+        assert_eq!(manifest.sent_count, 2);
+        assert_eq!(manifest.received_count, 2);
+        assert_eq!(manifest.hashes.len(), 3);
+
+        // The export reads back as the stored messages
+        let mut exported = vec![];
+        let mut reader = std::io::Cursor::new(&buf[..]);
+        while (reader.position() as usize) < buf.len() {
+            exported.push(proto::Message::decode_length_delimited(&mut reader).unwrap());
+        }
+        assert_eq!(exported, messages);
+        assert_eq!(
+            manifest.bytes,
+            messages.iter().map(|m| m.encoded_len()).sum::<usize>()
+        );
+
+        let mut buf = vec![];
+        export_messages(&db, "bob", Format::JsonLines, false, &mut buf).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(buf)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["from"], "alice");
+        assert_eq!(lines[0]["body"], BASE64URL_NOPAD.encode(b"yoyoyoyo"));
+    }
+
+    #[test]
+    fn rfc3339_test() {
+        let timestamp = |seconds, nanos| Some(proto::Timestamp { seconds, nanos });
+
+        assert_eq!(
+            to_rfc3339(&timestamp(1, 2)),
+            Some("1970-01-01T00:00:01.000000002+00:00".to_string())
+        );
+        assert_eq!(to_rfc3339(&None), None);
+        assert_eq!(to_rfc3339(&timestamp(1, -1)), None);
+        assert_eq!(to_rfc3339(&timestamp(1, 2_000_000_000)), None);
+        assert_eq!(to_rfc3339(&timestamp(i64::max_value(), 0)), None);
+        assert_eq!(to_rfc3339(&timestamp(i64::min_value(), 0)), None);
+    }
+}
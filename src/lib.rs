@@ -1,6 +1,8 @@
+extern crate aes_gcm;
 extern crate data_encoding;
 extern crate env_logger;
 extern crate futures;
+extern crate futures_cpupool;
 #[macro_use]
 extern crate log;
 extern crate chrono;
@@ -10,19 +12,27 @@ extern crate tower_hyper;
 extern crate failure;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 #[macro_use]
 extern crate lazy_static;
 extern crate foundationdb;
 extern crate instrumented;
 extern crate prost;
+extern crate rand;
+extern crate sha2;
+extern crate sled;
 extern crate switchroom_grpc;
 extern crate toml;
 extern crate url;
 extern crate yansi;
+extern crate zstd;
 
+pub mod backup;
 pub mod bloom_filter;
 pub mod config;
+pub mod export;
 pub mod messages;
 pub mod metrics;
 pub mod service;
 pub mod storage;
+pub mod validation;
@@ -14,7 +14,25 @@ impl Timestamped for proto::Message {
     }
 }
 
-fn get_timestamp() -> proto::Timestamp {
+impl Timestamped for proto::LegalHold {
+    fn timestamped(&self) -> Self {
+        proto::LegalHold {
+            placed_at: Some(get_timestamp()),
+            ..self.clone()
+        }
+    }
+}
+
+impl Timestamped for proto::BackupHeader {
+    fn timestamped(&self) -> Self {
+        proto::BackupHeader {
+            created_at: Some(get_timestamp()),
+            ..self.clone()
+        }
+    }
+}
+
+pub fn get_timestamp() -> proto::Timestamp {
     use std::time::SystemTime;
     let now = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -50,6 +68,7 @@ mod tests {
             }),
             signature: "signature".into(),
             value_cents: 0,
+            ttl_seconds: 0,
         };
     }
 
@@ -73,6 +92,7 @@ mod tests {
             }),
             signature: "signature".into(),
             value_cents: 0,
+            ttl_seconds: 0,
         };
         let new_message = message.timestamped();
         assert_eq!(new_message.from, "from id");
@@ -100,6 +120,7 @@ mod tests {
             }),
             signature: "signature".into(),
             value_cents: 0,
+            ttl_seconds: 0,
         };
         let new_message = message.timestamped();
         assert_eq!(new_message.from, "from id");
@@ -6,11 +6,41 @@ pub fn make_intcounter(name: &str, description: &str) -> prometheus::IntCounter
     counter
 }
 
+pub fn make_intcountervec(
+    name: &str,
+    description: &str,
+    labels: &[&str],
+) -> prometheus::IntCounterVec {
+    let counter =
+        prometheus::IntCounterVec::new(prometheus::Opts::new(name, description), labels).unwrap();
+    register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
 lazy_static! {
     pub static ref SEND_MESSAGE_CALLED: prometheus::IntCounter =
         make_intcounter("send_message_called_total", "Send message endpoint called");
+    pub static ref SEND_MESSAGES_CALLED: prometheus::IntCounter =
+        make_intcounter("send_messages_called_total", "Send messages endpoint called");
     pub static ref GET_MESSAGES_CALLED: prometheus::IntCounter =
         make_intcounter("get_messages_called_total", "Get messages endpoint called");
+    pub static ref DELETE_MESSAGE_CALLED: prometheus::IntCounter =
+        make_intcounter("delete_message_called_total", "Delete message endpoint called");
+    pub static ref SUBSCRIBE_MESSAGES_CALLED: prometheus::IntCounter =
+        make_intcounter("subscribe_messages_called_total", "Subscribe messages endpoint called");
+    pub static ref SYNC_CALLED: prometheus::IntCounter =
+        make_intcounter("sync_called_total", "Sync endpoint called");
+    pub static ref DUPLICATE_MESSAGE: prometheus::IntCounter =
+        make_intcounter("duplicate_message_total", "Message already stored, send was ignored");
+    pub static ref EXPIRED_MESSAGE: prometheus::IntCounter =
+        make_intcounter("expired_message_total", "Expired mailbox entry cleared");
+    pub static ref HELD_EXPIRED_MESSAGE: prometheus::IntCounter =
+        make_intcounter("held_expired_message_total", "Expired mailbox entry kept for legal hold");
     pub static ref MESSAGE_DECODE_FAILURE: prometheus::IntCounter =
         make_intcounter("message_decode_failure_total", "Message decoding failure");
+    pub static ref INVALID_MESSAGE: prometheus::IntCounterVec = make_intcountervec(
+        "invalid_message_total",
+        "Sent message rejected by validation",
+        &["reason"]
+    );
 }
@@ -1,11 +1,33 @@
+use crate::bloom_filter::BloomFilter;
+use crate::config;
+use crate::export;
 use crate::metrics;
 use crate::storage;
+use crate::validation;
 
-use futures::future;
+use data_encoding::BASE64URL_NOPAD;
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use futures_cpupool::CpuPool;
 use instrumented::{instrument, prometheus, register};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use switchroom_grpc::proto;
-use switchroom_grpc::tower_grpc::{Request, Response};
+use switchroom_grpc::tower_grpc::{Request, Response, Status};
+
+/// A request's result, which resolves once storage has answered. Handlers
+/// never wait on storage themselves, so the worker threads stay free for
+/// other requests.
+type RequestFuture<T> = Box<dyn Future<Item = T, Error = RequestError> + Send>;
+
+/// The messages sent on a `SubscribeMessages` stream.
+type MessageStream = Box<dyn Stream<Item = proto::Message, Error = RequestError> + Send>;
+
+/// The responses sent on a `Sync` stream.
+type SyncResponseStream = Box<dyn Stream<Item = proto::SyncResponse, Error = RequestError> + Send>;
+
+type GrpcFuture<T> = Box<dyn Future<Item = Response<T>, Error = Status> + Send>;
 
 #[derive(Debug, Fail)]
 enum RequestError {
@@ -13,18 +35,101 @@ enum RequestError {
     NotFound,
     #[fail(display = "Bad arguments specified for request: {:?}", err)]
     BadArguments { err: String },
-    #[fail(display = "Storage error: {:?}", err)]
-    StorageError { err: String },
+    #[fail(display = "Storage error: {}", err)]
+    StorageError { err: storage::StorageError },
+    #[fail(display = "Export failed: {}", err)]
+    ExportError { err: String },
+    #[fail(display = "Request stream failed: {}", err)]
+    StreamError { err: String },
+    #[fail(display = "Invalid message: {}", err)]
+    InvalidMessage { err: validation::ValidationError },
+}
+
+impl From<validation::ValidationError> for RequestError {
+    fn from(err: validation::ValidationError) -> RequestError {
+        RequestError::InvalidMessage { err }
+    }
 }
 
 impl From<storage::StorageError> for RequestError {
     fn from(err: storage::StorageError) -> RequestError {
-        RequestError::StorageError {
+        RequestError::StorageError { err }
+    }
+}
+
+impl From<export::ExportError> for RequestError {
+    fn from(err: export::ExportError) -> RequestError {
+        RequestError::ExportError {
             err: err.to_string(),
         }
     }
 }
 
+impl RequestError {
+    /// The status to fail the call with. Its `ErrorDetails` go in the
+    /// trailers, so that clients can tell whether to retry.
+    fn to_status(&self) -> Status {
+        use prost::Message;
+        use switchroom_grpc::proto::error_details::Reason;
+        use switchroom_grpc::tower_grpc::Code;
+
+        let (code, reason, retryable) = match self {
+            RequestError::NotFound => (Code::NotFound, Reason::NotFound, false),
+            RequestError::BadArguments { .. } => {
+                (Code::InvalidArgument, Reason::BadArguments, false)
+            }
+            RequestError::ExportError { .. } => (Code::Internal, Reason::Internal, false),
+            RequestError::StreamError { .. } => (Code::Cancelled, Reason::Cancelled, true),
+            RequestError::InvalidMessage { .. } => {
+                (Code::InvalidArgument, Reason::InvalidMessage, false)
+            }
+            RequestError::StorageError { err } => match err {
+                storage::StorageError::InvalidCursor => {
+                    (Code::InvalidArgument, Reason::InvalidCursor, false)
+                }
+                storage::StorageError::HashConflict => {
+                    (Code::AlreadyExists, Reason::HashConflict, false)
+                }
+                storage::StorageError::LegalHold { .. } => {
+                    (Code::FailedPrecondition, Reason::LegalHold, false)
+                }
+                storage::StorageError::Unavailable { .. }
+                | storage::StorageError::EmbeddedError { .. } => {
+                    (Code::Unavailable, Reason::StorageUnavailable, true)
+                }
+                storage::StorageError::Conflict { .. } => (Code::Aborted, Reason::Conflict, true),
+                storage::StorageError::Timeout { .. } => {
+                    (Code::DeadlineExceeded, Reason::Timeout, true)
+                }
+                storage::StorageError::Throttled { .. } => {
+                    (Code::ResourceExhausted, Reason::Throttled, true)
+                }
+                storage::StorageError::TooLarge { .. } => {
+                    (Code::ResourceExhausted, Reason::TooLarge, false)
+                }
+                storage::StorageError::EncodingFailure { .. }
+                | storage::StorageError::DecodingFailure { .. }
+                | storage::StorageError::FdbError { .. }
+                | storage::StorageError::KeyringError { .. }
+                | storage::StorageError::VersionTooOld { .. }
+                | storage::StorageError::SchemaMismatch { .. } => {
+                    (Code::Internal, Reason::Internal, false)
+                }
+            },
+        };
+
+        let details = proto::ErrorDetails {
+            reason: reason as i32,
+            retryable,
+        };
+        let mut buf = Vec::with_capacity(details.encoded_len());
+        details
+            .encode(&mut buf)
+            .expect("Unable to encode error details");
+        Status::with_details(code, self.to_string(), buf.into())
+    }
+}
+
 impl From<data_encoding::DecodeError> for RequestError {
     fn from(err: data_encoding::DecodeError) -> RequestError {
         RequestError::BadArguments {
@@ -57,103 +162,886 @@ lazy_static! {
 }
 #[derive(Clone)]
 pub struct Switchroom {
-    storage: Arc<storage::DB>,
+    storage: Arc<dyn storage::MessageStore>,
+    limits: config::Limits,
+}
+
+// Received times come from the clock of whichever instance stored a message,
+// so a message can be committed after one received later than it.
+// Subscriptions read this far back again, skipping what they've already sent.
+const SUBSCRIBE_LOOKBACK_SECONDS: i64 = 10;
+
+/// Hashes of messages a stream mustn't send, each with the received time its
+/// subscription has to pass before the hash can be forgotten.
+type SkipSet = Arc<Mutex<HashMap<Vec<u8>, proto::Timestamp>>>;
+
+/// How far a `SubscribeMessages` stream has got.
+struct Subscription {
+    storage: Arc<dyn storage::MessageStore>,
+    client_id: String,
+    /// The next read starts at this received time.
+    since: proto::Timestamp,
+    /// The received times of the messages already sent, by hash, back to
+    /// `since`.
+    sent: HashMap<Vec<u8>, proto::Timestamp>,
+    /// Other messages the client already has, which aren't sent. Trimmed
+    /// along with `sent`.
+    skip: SkipSet,
+    /// Set once a read has caught up with the mailbox, to the notification
+    /// token to wait on before reading again.
+    token: Option<Option<Vec<u8>>>,
+}
+
+/// Messages read from a mailbox to be sent on a stream.
+type SubscriptionStream =
+    Box<dyn Stream<Item = proto::Message, Error = storage::StorageError> + Send>;
+
+impl Subscription {
+    fn new(
+        storage: Arc<dyn storage::MessageStore>,
+        client_id: &str,
+        since: proto::Timestamp,
+        skip: SkipSet,
+    ) -> Self {
+        Subscription {
+            storage,
+            client_id: client_id.to_string(),
+            since,
+            sent: HashMap::new(),
+            skip,
+            token: None,
+        }
+    }
+
+    /// Every message received from `since` on, until the stream is dropped.
+    fn into_stream(self) -> SubscriptionStream {
+        let messages = stream::unfold(self, |subscription| Some(subscription.next_messages()));
+        Box::new(
+            messages
+                .map(stream::iter_ok::<_, storage::StorageError>)
+                .flatten(),
+        )
+    }
+
+    /// Wait for the mailbox to change if already caught up, then read the
+    /// messages not sent yet.
+    fn next_messages(mut self) -> storage::StorageFuture<(Vec<proto::Message>, Subscription)> {
+        let changed = match self.token.take() {
+            Some(token) => self.storage.watch_mailbox(&self.client_id, token),
+            None => Box::new(future::ok(())),
+        };
+
+        Box::new(changed.and_then(move |_| {
+            let sent: HashSet<Vec<u8>> = self.sent.keys().cloned().collect();
+            let skip = self.skip.clone();
+            let updates = self.storage.get_mailbox_updates(
+                &self.client_id,
+                self.since.clone(),
+                Box::new(move |hash| {
+                    !sent.contains(hash) && !skip.lock().unwrap().contains_key(hash)
+                }),
+            );
+            updates.map(move |updates| {
+                let messages = self.record(updates);
+                (messages, self)
+            })
+        }))
+    }
+
+    fn record(&mut self, updates: storage::MailboxUpdates) -> Vec<proto::Message> {
+        // A page cut short by the size limit is followed straight away
+        if updates.page.cursor.is_none() {
+            self.token = Some(updates.token);
+        }
+
+        for message in &updates.page.messages {
+            if let Some(ref received_at) = message.received_at {
+                self.sent.insert(message.hash.clone(), received_at.clone());
+            }
+        }
+        let newest = self
+            .sent
+            .values()
+            .map(|received_at| (received_at.seconds, received_at.nanos))
+            .max();
+        if let Some((seconds, nanos)) = newest {
+            let seconds = seconds - SUBSCRIBE_LOOKBACK_SECONDS;
+            if (seconds, nanos) > (self.since.seconds, self.since.nanos) {
+                self.since = proto::Timestamp { seconds, nanos };
+            }
+        }
+        let since = (self.since.seconds, self.since.nanos);
+        self.sent
+            .retain(|_, received_at| (received_at.seconds, received_at.nanos) >= since);
+        self.skip
+            .lock()
+            .unwrap()
+            .retain(|_, until| (until.seconds, until.nanos) >= since);
+
+        updates.page.messages
+    }
+}
+
+/// The bloom filter sent as a sketch, or `None` when the sketch is empty.
+fn decode_sketch(sketch: &str, salt: &str) -> Result<Option<BloomFilter>, RequestError> {
+    if sketch.is_empty() {
+        return Ok(None);
+    }
+    let filter_slice: Vec<u8> = BASE64URL_NOPAD.decode(sketch.as_bytes())?;
+    Ok(Some(BloomFilter::from_slice(&filter_slice, salt)))
+}
+
+/// Whether the client says it has the message with `hash`.
+fn in_sketch(bf: &BloomFilter, hash: &[u8]) -> bool {
+    bf.test(&BASE64URL_NOPAD.encode(hash))
+}
+
+/// Every message in `client_id`'s mailbox missing from `sketch`, a page at a
+/// time. Those received from `since` on are added to `skip` as they're sent.
+fn backlog_stream(
+    storage: Arc<dyn storage::MessageStore>,
+    client_id: &str,
+    sketch: Arc<Option<BloomFilter>>,
+    since: proto::Timestamp,
+    skip: SkipSet,
+) -> SubscriptionStream {
+    let client_id = client_id.to_string();
+
+    // The state is the cursor of the next page, or `None` after the last one
+    let pages = stream::unfold(Some(None), move |cursor| {
+        let cursor = cursor?;
+        let sketch = sketch.clone();
+        let filter_skip = skip.clone();
+        let filter: storage::HashFilter = Box::new(move |hash| {
+            let in_sketch = match *sketch {
+                Some(ref bf) => in_sketch(bf, hash),
+                None => false,
+            };
+            !in_sketch && !filter_skip.lock().unwrap().contains_key(hash)
+        });
+
+        let skip = skip.clone();
+        let since = (since.seconds, since.nanos);
+        let page = storage
+            .get_messages_for(&client_id, cursor, filter)
+            .map(move |page| {
+                let mut skip = skip.lock().unwrap();
+                for message in &page.messages {
+                    if let Some(ref received_at) = message.received_at {
+                        if (received_at.seconds, received_at.nanos) >= since {
+                            skip.insert(message.hash.clone(), received_at.clone());
+                        }
+                    }
+                }
+                (page.messages, page.cursor.map(Some))
+            });
+        Some(page)
+    });
+
+    Box::new(
+        pages
+            .map(stream::iter_ok::<_, storage::StorageError>)
+            .flatten(),
+    )
+}
+
+/// Count a call to the handler `name` under the same metrics as
+/// `#[instrument]`, timing it until `request` resolves rather than until the
+/// future is built.
+fn instrument_request<T: Send + 'static>(
+    name: &'static str,
+    request: RequestFuture<T>,
+) -> RequestFuture<T> {
+    instrumented::inc_called_counter_for(name, "default");
+    instrumented::inc_inflight_for(name, "default");
+    let timer = instrumented::get_timer_for(name, "default");
+    Box::new(request.then(move |result| {
+        if let Err(ref err) = result {
+            instrumented::inc_error_counter_for(name, "default", format!("{:?}", err));
+        }
+        instrumented::dec_inflight_for(name, "default");
+        timer.observe_duration();
+        result
+    }))
+}
+
+/// Count a stored message in the metrics.
+fn record_inserted(inserted: &storage::InsertedMessage) {
+    // A retry of a message we already have shouldn't count twice
+    if inserted.duplicate {
+        metrics::DUPLICATE_MESSAGE.inc();
+        return;
+    }
+
+    MESSAGE_VALUE_HISTO
+        .with_label_values(&[])
+        .observe(f64::from(inserted.message.value_cents));
+    MESSAGE_VALUE_COUNTER
+        .with_label_values(&[])
+        .inc_by(i64::from(inserted.message.value_cents));
+}
+
+/// The filter and cursor for a `GetMessages` request.
+fn get_messages_args(
+    request: &proto::GetMessagesRequest,
+) -> Result<(storage::HashFilter, Option<Vec<u8>>), RequestError> {
+    let filter: storage::HashFilter = match decode_sketch(&request.sketch, &request.salt)? {
+        // If the sketch is empty, return the full set of messages
+        None => Box::new(|_| true),
+        // If a sketch was provided, filter out messages that are present in the bloom filter
+        Some(bf) => Box::new(move |hash| !in_sketch(&bf, hash)),
+    };
+
+    // An empty cursor starts from the beginning of the mailbox
+    let cursor = if request.cursor.is_empty() {
+        None
+    } else {
+        Some(BASE64URL_NOPAD.decode(request.cursor.as_bytes())?)
+    };
+
+    Ok((filter, cursor))
 }
 
 impl Switchroom {
-    pub fn new(storage: Arc<storage::DB>) -> Self {
-        Switchroom { storage }
+    pub fn new(storage: Arc<dyn storage::MessageStore>) -> Self {
+        Switchroom {
+            storage,
+            limits: config::Limits::default(),
+        }
     }
 
-    #[instrument(DEBUG)]
-    fn handle_send_message(
+    /// Limits sent messages are checked against, in place of the defaults.
+    pub fn with_limits(mut self, limits: config::Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    fn handle_send_message(&self, message: &proto::Message) -> RequestFuture<proto::Message> {
+        use crate::messages::Timestamped;
+
+        let sent: RequestFuture<_> = match validation::check_message(message, &self.limits) {
+            Ok(()) => Box::new(
+                self.storage
+                    .insert_message(message.timestamped())
+                    .from_err()
+                    .map(|inserted| {
+                        record_inserted(&inserted);
+                        inserted.message
+                    }),
+            ),
+            Err(err) => Box::new(future::err(err.into())),
+        };
+        instrument_request("handle_send_message", sent)
+    }
+
+    fn handle_send_messages(
         &self,
-        message: &proto::Message,
-    ) -> Result<proto::Message, RequestError> {
+        request: &proto::SendMessagesRequest,
+    ) -> RequestFuture<proto::SendMessagesResponse> {
         use crate::messages::Timestamped;
-        use futures::Future;
-        let message = self.storage.insert_message(message.timestamped()).wait()?;
+        use switchroom_grpc::proto::send_message_result::Status;
 
-        MESSAGE_VALUE_HISTO
-            .with_label_values(&[])
-            .observe(f64::from(message.value_cents));
-        MESSAGE_VALUE_COUNTER
-            .with_label_values(&[])
-            .inc_by(i64::from(message.value_cents));
+        // Invalid messages are rejected without being stored, and the rest
+        // are stored together
+        let checked: Vec<Result<(), validation::ValidationError>> = request
+            .messages
+            .iter()
+            .map(|m| validation::check_message(m, &self.limits))
+            .collect();
+        let messages = request
+            .messages
+            .iter()
+            .zip(&checked)
+            .filter(|(_, check)| check.is_ok())
+            .map(|(m, _)| m.timestamped())
+            .collect();
+        Box::new(
+            self.storage
+                .insert_messages(messages)
+                .from_err()
+                .map(|results| {
+                    let mut stored = results.into_iter();
+                    let results = checked
+                        .into_iter()
+                        .map(|check| match check {
+                            Ok(()) => stored
+                                .next()
+                                .expect("A result for every valid message")
+                                .map_err(|err| err.to_string()),
+                            Err(err) => Err(err.to_string()),
+                        })
+                        .map(|result| match result {
+                            Ok(inserted) => {
+                                record_inserted(&inserted);
+                                let status = if inserted.duplicate {
+                                    Status::Duplicate
+                                } else {
+                                    Status::Stored
+                                };
+                                proto::SendMessageResult {
+                                    status: status as i32,
+                                    message: Some(inserted.message),
+                                    reason: "".into(),
+                                }
+                            }
+                            Err(err) => proto::SendMessageResult {
+                                status: Status::Rejected as i32,
+                                message: None,
+                                reason: err,
+                            },
+                        })
+                        .collect();
+                    proto::SendMessagesResponse { results }
+                }),
+        )
+    }
 
-        Ok(message)
+    fn handle_delete_message(
+        &self,
+        request: &proto::DeleteMessageRequest,
+    ) -> RequestFuture<proto::DeleteMessageResponse> {
+        Box::new(
+            self.storage
+                .delete_message(&request.client_id, request.hash.clone())
+                .from_err()
+                .and_then(|deleted| match deleted {
+                    Some(_) => Ok(proto::DeleteMessageResponse {}),
+                    None => Err(RequestError::NotFound),
+                }),
+        )
+    }
+
+    /// Serving as long as storage can be read.
+    fn handle_check(
+        &self,
+        _request: &proto::HealthCheckRequest,
+    ) -> RequestFuture<proto::HealthCheckResponse> {
+        use switchroom_grpc::proto::health_check_response::ServingStatus;
+
+        Box::new(self.storage.schema_version().then(|result| {
+            let status = match result {
+                Ok(_) => ServingStatus::Serving,
+                Err(err) => {
+                    error!("Health check failed, storage is unavailable: {}", err);
+                    ServingStatus::NotServing
+                }
+            };
+            Ok(proto::HealthCheckResponse {
+                status: status as i32,
+            })
+        }))
     }
 
-    #[instrument(DEBUG)]
     fn handle_get_messages(
         &self,
         request: &proto::GetMessagesRequest,
-    ) -> Result<proto::GetMessagesResponse, RequestError> {
-        use crate::bloom_filter::BloomFilter;
-        use data_encoding::BASE64URL_NOPAD;
-        use futures::Future;
-
-        if request.sketch.is_empty() {
-            // If the sketch is empty, return the full set of messages
-            let messages = self
-                .storage
-                .get_messages_for(&request.client_id, |_| true)
-                .wait()?;
-            Ok(proto::GetMessagesResponse { messages })
+    ) -> RequestFuture<proto::GetMessagesResponse> {
+        let (filter, cursor) = match get_messages_args(request) {
+            Ok(args) => args,
+            Err(err) => {
+                return instrument_request("handle_get_messages", Box::new(future::err(err)))
+            }
+        };
+
+        let page = if request.since.is_none() && request.until.is_none() {
+            self.storage
+                .get_messages_for(&request.client_id, cursor, filter)
         } else {
-            // If a sketch was provided, filter out messages that are present in the bloom filter
-            let filter_slice: Vec<u8> = BASE64URL_NOPAD.decode(request.sketch.as_bytes())?;
-            let bf = BloomFilter::from_slice(&filter_slice, request.salt.as_ref());
-            let messages = self
-                .storage
-                .get_messages_for(&request.client_id, move |hash| {
-                    let hash = BASE64URL_NOPAD.encode(hash);
-                    !bf.test(&hash)
+            // Only read the requested window of the mailbox
+            let range = storage::TimeRange {
+                since: request.since.clone(),
+                until: request.until.clone(),
+            };
+            self.storage
+                .get_messages_between(&request.client_id, range, cursor, filter)
+        };
+
+        let response = page.from_err().map(|page| proto::GetMessagesResponse {
+            messages: page.messages,
+            cursor: page
+                .cursor
+                .map(|cursor| BASE64URL_NOPAD.encode(&cursor))
+                .unwrap_or_default(),
+        });
+        instrument_request("handle_get_messages", Box::new(response))
+    }
+
+    /// Streams until the caller hangs up or storage fails.
+    fn handle_subscribe_messages(
+        &self,
+        request: &proto::SubscribeMessagesRequest,
+    ) -> Result<MessageStream, RequestError> {
+        use crate::messages::get_timestamp;
+
+        if request.client_id.is_empty() {
+            return Err(RequestError::BadArguments {
+                err: "client_id is required".to_string(),
+            });
+        }
+
+        let subscription = Subscription::new(
+            self.storage.clone(),
+            &request.client_id,
+            request.since.clone().unwrap_or_else(get_timestamp),
+            Arc::new(Mutex::new(HashMap::new())),
+        );
+
+        Ok(Box::new(
+            subscription.into_stream().map_err(RequestError::from),
+        ))
+    }
+
+    /// Starts once the first request, which must be a `SyncStart`, arrives.
+    /// The response streams until the caller hangs up or storage fails, and
+    /// acks are taken in as they arrive.
+    fn handle_sync<S>(&self, requests: S) -> RequestFuture<SyncResponseStream>
+    where
+        S: Stream<Item = proto::SyncRequest, Error = RequestError> + Send + 'static,
+    {
+        use crate::messages::get_timestamp;
+        use switchroom_grpc::proto::sync_request::Request as SyncRequest;
+        use switchroom_grpc::proto::sync_response::Response as SyncResponse;
+
+        let storage = self.storage.clone();
+        let max_skipped = self.limits.max_sync_skipped;
+        let sync = requests
+            .into_future()
+            .map_err(|(err, _requests)| err)
+            .and_then(move |(first, requests)| {
+                let start = match first.and_then(|first| first.request) {
+                    Some(SyncRequest::Start(start)) => start,
+                    _ => {
+                        return Err(RequestError::BadArguments {
+                            err: "the first request must be a start".to_string(),
+                        })
+                    }
+                };
+                if start.client_id.is_empty() {
+                    return Err(RequestError::BadArguments {
+                        err: "client_id is required".to_string(),
+                    });
+                }
+                let sketch = Arc::new(decode_sketch(&start.sketch, &start.salt)?);
+
+                // Messages received from here on are sent as they arrive, so
+                // the backlog only has to skip the ones it sent itself
+                let since = get_timestamp();
+                let skip = Arc::new(Mutex::new(HashMap::new()));
+                let backlog = backlog_stream(
+                    storage.clone(),
+                    &start.client_id,
+                    sketch,
+                    since.clone(),
+                    skip.clone(),
+                );
+                let live = Subscription::new(storage, &start.client_id, since, skip.clone());
+
+                let responses = backlog
+                    .map(SyncResponse::Message)
+                    .chain(stream::once(Ok(SyncResponse::CaughtUp(
+                        proto::SyncCaughtUp {},
+                    ))))
+                    .chain(live.into_stream().map(SyncResponse::Message))
+                    .map(|response| proto::SyncResponse {
+                        response: Some(response),
+                    })
+                    .map_err(RequestError::from);
+
+                // Polled alongside the responses, so acks are taken in even
+                // while the responses wait for new messages
+                let acks = requests
+                    .and_then(move |request| match request.request {
+                        Some(SyncRequest::Ack(ack)) => {
+                            for hash in &ack.hashes {
+                                validation::validate_hash(hash).map_err(|err| {
+                                    RequestError::BadArguments {
+                                        err: format!("acked {}", err),
+                                    }
+                                })?;
+                            }
+                            let mut skip = skip.lock().unwrap();
+                            if skip.len() + ack.hashes.len() > max_skipped {
+                                return Err(RequestError::BadArguments {
+                                    err: format!(
+                                        "over {} hashes acked and not yet passed",
+                                        max_skipped
+                                    ),
+                                });
+                            }
+                            // The client has these already, so they were
+                            // received before now, give or take the clocks
+                            // of other instances
+                            let mut until = get_timestamp();
+                            until.seconds += SUBSCRIBE_LOOKBACK_SECONDS;
+                            for hash in ack.hashes {
+                                skip.insert(hash, until.clone());
+                            }
+                            Ok(())
+                        }
+                        _ => Err(RequestError::BadArguments {
+                            err: "only acks may follow the start".to_string(),
+                        }),
+                    })
+                    .filter_map(|()| None);
+
+                let responses: SyncResponseStream = Box::new(responses.select(acks));
+                Ok(responses)
+            });
+
+        Box::new(sync)
+    }
+}
+
+/// The operator endpoints, which clients must not be able to reach.
+#[derive(Clone)]
+pub struct SwitchroomAdmin {
+    storage: Arc<dyn storage::MessageStore>,
+    /// Runs exports, which write their files synchronously. Exports run one
+    /// at a time, away from both the worker threads and the storage threads
+    /// they wait on.
+    export_pool: CpuPool,
+    /// Exports are written under this directory, and refused without one.
+    export_dir: Option<PathBuf>,
+}
+
+/// Where to write an export requested at `path`, which must be relative and
+/// stay inside `dir`.
+fn export_path(dir: &Path, path: &str) -> Result<PathBuf, RequestError> {
+    let path = Path::new(path);
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => (),
+            _ => {
+                return Err(RequestError::BadArguments {
+                    err: format!(
+                        "path must be relative to the export directory: {}",
+                        path.display()
+                    ),
                 })
-                .wait()?;
-            Ok(proto::GetMessagesResponse { messages })
+            }
         }
     }
+    Ok(dir.join(path))
+}
+
+impl SwitchroomAdmin {
+    pub fn new(storage: Arc<dyn storage::MessageStore>) -> Self {
+        SwitchroomAdmin {
+            storage,
+            export_pool: CpuPool::new(1),
+            export_dir: None,
+        }
+    }
+
+    /// Allow exports, written under `dir`.
+    pub fn with_export_dir(mut self, dir: &str) -> Self {
+        self.export_dir = Some(PathBuf::from(dir));
+        self
+    }
+
+    fn handle_add_legal_hold(&self, hold: &proto::LegalHold) -> RequestFuture<proto::LegalHold> {
+        use crate::messages::Timestamped;
+
+        if hold.client_id.is_empty() {
+            return Box::new(future::err(RequestError::BadArguments {
+                err: "client_id is required".to_string(),
+            }));
+        }
+        let hold = hold.timestamped();
+        Box::new(
+            self.storage
+                .add_legal_hold(hold.clone())
+                .from_err()
+                .map(move |_| {
+                    info!("Placed legal hold on {}: {}", hold.client_id, hold.reason);
+                    hold
+                }),
+        )
+    }
+
+    fn handle_remove_legal_hold(
+        &self,
+        request: &proto::RemoveLegalHoldRequest,
+    ) -> RequestFuture<proto::RemoveLegalHoldResponse> {
+        let client_id = request.client_id.clone();
+        Box::new(
+            self.storage
+                .remove_legal_hold(&request.client_id)
+                .from_err()
+                .and_then(move |removed| {
+                    if removed {
+                        info!("Lifted legal hold on {}", client_id);
+                        Ok(proto::RemoveLegalHoldResponse {})
+                    } else {
+                        Err(RequestError::NotFound)
+                    }
+                }),
+        )
+    }
+
+    fn handle_purge_client(
+        &self,
+        request: &proto::PurgeClientRequest,
+    ) -> RequestFuture<proto::PurgeClientResponse> {
+        if request.client_id.is_empty() {
+            return Box::new(future::err(RequestError::BadArguments {
+                err: "client_id is required".to_string(),
+            }));
+        }
+
+        let storage = self.storage.clone();
+        let client_id = request.client_id.clone();
+        let purge = future::loop_fn(
+            proto::PurgeClientResponse::default(),
+            move |mut response| {
+                storage
+                    .purge_client(&client_id, storage::BATCH_SIZE)
+                    .map(move |(purged, more)| {
+                        response.messages += purged.messages as i64;
+                        response.bytes += purged.bytes as i64;
+                        response.held += purged.held as i64;
+                        if more {
+                            Loop::Continue(response)
+                        } else {
+                            Loop::Break(response)
+                        }
+                    })
+            },
+        );
+
+        let client_id = request.client_id.clone();
+        Box::new(purge.from_err().map(move |response| {
+            info!(
+                "Purged {} messages ({} bytes) for {}",
+                response.messages, response.bytes, client_id
+            );
+            response
+        }))
+    }
+
+    /// Runs on `export_pool`, since it blocks until the export is written.
+    #[instrument(DEBUG)]
+    fn handle_export_messages(
+        &self,
+        request: &proto::ExportMessagesRequest,
+    ) -> Result<proto::ExportMessagesResponse, RequestError> {
+        use switchroom_grpc::proto::export_messages_request::Format;
+
+        if request.client_id.is_empty() || request.path.is_empty() {
+            return Err(RequestError::BadArguments {
+                err: "client_id and path are required".to_string(),
+            });
+        }
+        let format = match Format::from_i32(request.format) {
+            Some(Format::Protobuf) => export::Format::Protobuf,
+            Some(Format::JsonLines) => export::Format::JsonLines,
+            None => {
+                return Err(RequestError::BadArguments {
+                    err: format!("unknown format {}", request.format),
+                })
+            }
+        };
+        let path = match self.export_dir {
+            Some(ref dir) => export_path(dir, &request.path)?,
+            None => {
+                return Err(RequestError::BadArguments {
+                    err: "exports are disabled, since no export_dir is configured".to_string(),
+                })
+            }
+        };
+        let path = path.to_string_lossy();
+
+        let manifest = export::export_to_file(
+            &*self.storage,
+            &request.client_id,
+            format,
+            request.include_expired,
+            &path,
+        )?;
+        info!(
+            "Exported {} messages for {} to {}",
+            manifest.message_count, request.client_id, path
+        );
+
+        Ok(proto::ExportMessagesResponse {
+            manifest_path: export::manifest_path(&path),
+            message_count: manifest.message_count as i64,
+            bytes: manifest.bytes as i64,
+        })
+    }
+
+    fn handle_list_legal_holds(
+        &self,
+        _request: &proto::ListLegalHoldsRequest,
+    ) -> RequestFuture<proto::ListLegalHoldsResponse> {
+        Box::new(
+            self.storage
+                .list_legal_holds()
+                .from_err()
+                .map(|holds| proto::ListLegalHoldsResponse { holds }),
+        )
+    }
 }
 
 impl proto::server::Switchroom for Switchroom {
-    type SendMessageFuture =
-        future::FutureResult<Response<proto::Message>, switchroom_grpc::tower_grpc::Status>;
+    type SendMessageFuture = GrpcFuture<proto::Message>;
     fn send_message(&mut self, request: Request<proto::Message>) -> Self::SendMessageFuture {
-        use futures::future::IntoFuture;
-        use switchroom_grpc::tower_grpc::{Code, Status};
         metrics::SEND_MESSAGE_CALLED.inc();
-        self.handle_send_message(request.get_ref())
-            .map(Response::new)
-            .map_err(|err| Status::new(Code::InvalidArgument, err.to_string()))
-            .into_future()
+        Box::new(
+            self.handle_send_message(request.get_ref())
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
     }
 
-    type GetMessagesFuture = future::FutureResult<
-        Response<proto::GetMessagesResponse>,
-        switchroom_grpc::tower_grpc::Status,
-    >;
+    type SendMessagesFuture = GrpcFuture<proto::SendMessagesResponse>;
+    fn send_messages(
+        &mut self,
+        request: Request<proto::SendMessagesRequest>,
+    ) -> Self::SendMessagesFuture {
+        metrics::SEND_MESSAGES_CALLED.inc();
+        Box::new(
+            self.handle_send_messages(request.get_ref())
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
+    }
+
+    type GetMessagesFuture = GrpcFuture<proto::GetMessagesResponse>;
     fn get_messages(
         &mut self,
         request: Request<proto::GetMessagesRequest>,
     ) -> Self::GetMessagesFuture {
-        use futures::future::IntoFuture;
-        use switchroom_grpc::tower_grpc::{Code, Status};
         metrics::GET_MESSAGES_CALLED.inc();
-        self.handle_get_messages(request.get_ref())
+        Box::new(
+            self.handle_get_messages(request.get_ref())
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
+    }
+
+    type DeleteMessageFuture = GrpcFuture<proto::DeleteMessageResponse>;
+    fn delete_message(
+        &mut self,
+        request: Request<proto::DeleteMessageRequest>,
+    ) -> Self::DeleteMessageFuture {
+        metrics::DELETE_MESSAGE_CALLED.inc();
+        Box::new(
+            self.handle_delete_message(request.get_ref())
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
+    }
+
+    type SubscribeMessagesStream = Box<dyn Stream<Item = proto::Message, Error = Status> + Send>;
+    type SubscribeMessagesFuture =
+        future::FutureResult<Response<Self::SubscribeMessagesStream>, Status>;
+    fn subscribe_messages(
+        &mut self,
+        request: Request<proto::SubscribeMessagesRequest>,
+    ) -> Self::SubscribeMessagesFuture {
+        use futures::future::IntoFuture;
+        metrics::SUBSCRIBE_MESSAGES_CALLED.inc();
+        self.handle_subscribe_messages(request.get_ref())
+            .map(|messages| -> Self::SubscribeMessagesStream {
+                Box::new(messages.map_err(|err| err.to_status()))
+            })
             .map(Response::new)
-            .map_err(|err| Status::new(Code::InvalidArgument, err.to_string()))
+            .map_err(|err| err.to_status())
             .into_future()
     }
 
-    type CheckFuture = future::FutureResult<
-        Response<proto::HealthCheckResponse>,
-        switchroom_grpc::tower_grpc::Status,
-    >;
-    fn check(&mut self, _request: Request<proto::HealthCheckRequest>) -> Self::CheckFuture {
-        future::ok(Response::new(proto::HealthCheckResponse {
-            status: proto::health_check_response::ServingStatus::Serving as i32,
-        }))
+    type SyncStream = Box<dyn Stream<Item = proto::SyncResponse, Error = Status> + Send>;
+    type SyncFuture = GrpcFuture<Self::SyncStream>;
+    fn sync(
+        &mut self,
+        request: Request<switchroom_grpc::tower_grpc::Streaming<proto::SyncRequest>>,
+    ) -> Self::SyncFuture {
+        metrics::SYNC_CALLED.inc();
+        let requests = request
+            .into_inner()
+            .map_err(|status| RequestError::StreamError {
+                err: status.message().to_string(),
+            });
+        Box::new(
+            self.handle_sync(requests)
+                .map(|responses| -> Self::SyncStream {
+                    Box::new(responses.map_err(|err| err.to_status()))
+                })
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
+    }
+
+    type CheckFuture = GrpcFuture<proto::HealthCheckResponse>;
+    fn check(&mut self, request: Request<proto::HealthCheckRequest>) -> Self::CheckFuture {
+        Box::new(
+            self.handle_check(request.get_ref())
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
+    }
+}
+
+impl proto::server::SwitchroomAdmin for SwitchroomAdmin {
+    type AddLegalHoldFuture = GrpcFuture<proto::LegalHold>;
+    fn add_legal_hold(&mut self, request: Request<proto::LegalHold>) -> Self::AddLegalHoldFuture {
+        Box::new(
+            self.handle_add_legal_hold(request.get_ref())
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
+    }
+
+    type RemoveLegalHoldFuture = GrpcFuture<proto::RemoveLegalHoldResponse>;
+    fn remove_legal_hold(
+        &mut self,
+        request: Request<proto::RemoveLegalHoldRequest>,
+    ) -> Self::RemoveLegalHoldFuture {
+        Box::new(
+            self.handle_remove_legal_hold(request.get_ref())
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
+    }
+
+    type ListLegalHoldsFuture = GrpcFuture<proto::ListLegalHoldsResponse>;
+    fn list_legal_holds(
+        &mut self,
+        request: Request<proto::ListLegalHoldsRequest>,
+    ) -> Self::ListLegalHoldsFuture {
+        Box::new(
+            self.handle_list_legal_holds(request.get_ref())
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
+    }
+
+    type PurgeClientFuture = GrpcFuture<proto::PurgeClientResponse>;
+    fn purge_client(
+        &mut self,
+        request: Request<proto::PurgeClientRequest>,
+    ) -> Self::PurgeClientFuture {
+        Box::new(
+            self.handle_purge_client(request.get_ref())
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
+    }
+
+    type ExportMessagesFuture = GrpcFuture<proto::ExportMessagesResponse>;
+    fn export_messages(
+        &mut self,
+        request: Request<proto::ExportMessagesRequest>,
+    ) -> Self::ExportMessagesFuture {
+        let admin = self.clone();
+        let request = request.into_inner();
+        Box::new(
+            self.export_pool
+                .spawn_fn(move || admin.handle_export_messages(&request))
+                .map(Response::new)
+                .map_err(|err| err.to_status()),
+        )
     }
 }
 
@@ -161,4 +1049,619 @@ impl proto::server::Switchroom for Switchroom {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::storage::memory::MemoryDB;
+    use futures::Future;
+
+    fn make_switchroom() -> Switchroom {
+        Switchroom::new(Arc::new(MemoryDB::new(30)))
+    }
+
+    // A valid hash, which starts with the label
+    fn make_hash(label: &str) -> Vec<u8> {
+        let mut hash = label.as_bytes().to_vec();
+        hash.resize(validation::HASH_LENGTH, 0);
+        hash
+    }
+
+    fn make_message(hash: &str, from: &str, to: &str) -> proto::Message {
+        proto::Message {
+            hash: make_hash(hash),
+            ..storage::testing::make_message("", from, to, "yoyoyoyo".into())
+        }
+    }
+
+    #[test]
+    fn health_check() {
+        let switchroom = make_switchroom();
+
+        let response = switchroom
+            .handle_check(&proto::HealthCheckRequest {
+                service: "switchroom".into(),
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(
+            response.status,
+            proto::health_check_response::ServingStatus::Serving as i32
+        );
+    }
+
+    #[test]
+    fn send_and_get_messages() {
+        let switchroom = make_switchroom();
+
+        let message = make_message("hash", "alice", "bob");
+        let stored = switchroom.handle_send_message(&message).wait().unwrap();
+        assert_eq!(stored.body, message.body);
+        assert_eq!(stored.received_at.is_some(), true);
+
+        for client_id in &["alice", "bob"] {
+            let response = switchroom
+                .handle_get_messages(&proto::GetMessagesRequest {
+                    client_id: client_id.to_string(),
+                    sketch: "".into(),
+                    salt: "".into(),
+                    cursor: "".into(),
+                    since: None,
+                    until: None,
+                })
+                .wait()
+                .unwrap();
+            assert_eq!(response.messages, vec![stored.clone()]);
+            assert_eq!(response.cursor, "");
+        }
+    }
+
+    #[test]
+    fn send_message_is_idempotent() {
+        let switchroom = make_switchroom();
+
+        let message = make_message("hash", "alice", "bob");
+        let first = switchroom.handle_send_message(&message).wait().unwrap();
+        let second = switchroom.handle_send_message(&message).wait().unwrap();
+        assert_eq!(second, first);
+
+        let response = switchroom
+            .handle_get_messages(&proto::GetMessagesRequest {
+                client_id: "bob".into(),
+                sketch: "".into(),
+                salt: "".into(),
+                cursor: "".into(),
+                since: None,
+                until: None,
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(response.messages, vec![first]);
+    }
+
+    #[test]
+    fn send_messages() {
+        use switchroom_grpc::proto::send_message_result::Status;
+
+        let switchroom = make_switchroom();
+        let stored = switchroom
+            .handle_send_message(&make_message("hash 1", "alice", "bob"))
+            .wait()
+            .unwrap();
+
+        let request = proto::SendMessagesRequest {
+            messages: vec![
+                make_message("hash 1", "alice", "bob"),
+                make_message("hash 1", "mallory", "bob"),
+                make_message("hash 2", "alice", "bob"),
+                make_message("hash 3", "", "bob"),
+            ],
+        };
+        let results = switchroom
+            .handle_send_messages(&request)
+            .wait()
+            .unwrap()
+            .results;
+        let statuses: Vec<i32> = results.iter().map(|result| result.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                Status::Duplicate as i32,
+                Status::Rejected as i32,
+                Status::Stored as i32,
+                Status::Rejected as i32
+            ]
+        );
+        assert_eq!(results[0].message, Some(stored));
+        assert_eq!(results[1].reason.is_empty(), false);
+        assert_eq!(
+            results[2].message.as_ref().unwrap().received_at.is_some(),
+            true
+        );
+        assert_eq!(results[3].reason, "from client ID is empty");
+    }
+
+    #[test]
+    fn get_messages_pages_with_cursor() {
+        let switchroom = make_switchroom();
+
+        let mut message = make_message("", "alice", "bob");
+        message.body = vec![0u8; 1_048_576];
+        for n in 0..12 {
+            message.hash = make_hash(&format!("hash{:02}", n));
+            switchroom.handle_send_message(&message).wait().unwrap();
+        }
+
+        let mut request = proto::GetMessagesRequest {
+            client_id: "bob".into(),
+            sketch: "".into(),
+            salt: "".into(),
+            cursor: "".into(),
+            since: None,
+            until: None,
+        };
+        let first = switchroom.handle_get_messages(&request).wait().unwrap();
+        assert_eq!(first.cursor.is_empty(), false);
+
+        request.cursor = first.cursor;
+        let second = switchroom.handle_get_messages(&request).wait().unwrap();
+        assert_eq!(second.cursor, "");
+        assert_eq!(first.messages.len() + second.messages.len(), 12);
+    }
+
+    #[test]
+    fn delete_message() {
+        let switchroom = make_switchroom();
+
+        let stored = switchroom
+            .handle_send_message(&make_message("hash", "alice", "bob"))
+            .wait()
+            .unwrap();
+
+        let request = proto::DeleteMessageRequest {
+            client_id: "mallory".into(),
+            hash: stored.hash.clone(),
+        };
+        assert_eq!(
+            switchroom.handle_delete_message(&request).wait().is_err(),
+            true
+        );
+
+        let request = proto::DeleteMessageRequest {
+            client_id: "alice".into(),
+            hash: stored.hash.clone(),
+        };
+        assert_eq!(
+            switchroom.handle_delete_message(&request).wait().is_ok(),
+            true
+        );
+        assert_eq!(
+            switchroom.handle_delete_message(&request).wait().is_err(),
+            true
+        );
+
+        let response = switchroom
+            .handle_get_messages(&proto::GetMessagesRequest {
+                client_id: "bob".into(),
+                sketch: "".into(),
+                salt: "".into(),
+                cursor: "".into(),
+                since: None,
+                until: None,
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(response.messages.len(), 0);
+    }
+
+    #[test]
+    fn legal_hold_blocks_delete() {
+        let storage: Arc<dyn storage::MessageStore> = Arc::new(MemoryDB::new(30));
+        let switchroom = Switchroom::new(storage.clone());
+        let admin = SwitchroomAdmin::new(storage);
+
+        let stored = switchroom
+            .handle_send_message(&make_message("hash", "alice", "bob"))
+            .wait()
+            .unwrap();
+
+        let hold = admin
+            .handle_add_legal_hold(&proto::LegalHold {
+                client_id: "bob".into(),
+                reason: "case 1".into(),
+                placed_at: None,
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(hold.placed_at.is_some(), true);
+        let response = admin
+            .handle_list_legal_holds(&proto::ListLegalHoldsRequest {})
+            .wait()
+            .unwrap();
+        assert_eq!(response.holds, vec![hold]);
+
+        // Either participant's hold keeps the message
+        let request = proto::DeleteMessageRequest {
+            client_id: "alice".into(),
+            hash: stored.hash.clone(),
+        };
+        assert_eq!(
+            switchroom.handle_delete_message(&request).wait().is_err(),
+            true
+        );
+
+        let release = proto::RemoveLegalHoldRequest {
+            client_id: "bob".into(),
+        };
+        assert_eq!(
+            admin.handle_remove_legal_hold(&release).wait().is_ok(),
+            true
+        );
+        assert_eq!(
+            admin.handle_remove_legal_hold(&release).wait().is_err(),
+            true
+        );
+        assert_eq!(
+            switchroom.handle_delete_message(&request).wait().is_ok(),
+            true
+        );
+    }
+
+    #[test]
+    fn export_messages() {
+        let storage: Arc<dyn storage::MessageStore> = Arc::new(MemoryDB::new(30));
+        let switchroom = Switchroom::new(storage.clone());
+        switchroom
+            .handle_send_message(&make_message("hash 1", "alice", "bob"))
+            .wait()
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("switchroom-export-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let request = |path: &str| proto::ExportMessagesRequest {
+            client_id: "bob".into(),
+            path: path.into(),
+            format: 0,
+            include_expired: false,
+        };
+
+        // Refused without an export directory
+        let admin = SwitchroomAdmin::new(storage.clone());
+        assert_eq!(admin.handle_export_messages(&request("bob")).is_err(), true);
+
+        let admin = SwitchroomAdmin::new(storage).with_export_dir(dir.to_str().unwrap());
+        let response = admin.handle_export_messages(&request("bob")).unwrap();
+        assert_eq!(response.message_count, 1);
+        assert_eq!(dir.join("bob").exists(), true);
+        assert_eq!(
+            response.manifest_path,
+            dir.join("bob.manifest.json").to_string_lossy()
+        );
+
+        // Paths can't leave the directory
+        for path in &["/tmp/bob", "../bob", "exports/../../bob"] {
+            assert_eq!(admin.handle_export_messages(&request(path)).is_err(), true);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn purge_client() {
+        let storage: Arc<dyn storage::MessageStore> = Arc::new(MemoryDB::new(30));
+        let switchroom = Switchroom::new(storage.clone());
+        let admin = SwitchroomAdmin::new(storage);
+
+        switchroom
+            .handle_send_message(&make_message("hash 1", "alice", "bob"))
+            .wait()
+            .unwrap();
+        switchroom
+            .handle_send_message(&make_message("hash 2", "bob", "alice"))
+            .wait()
+            .unwrap();
+
+        let response = admin
+            .handle_purge_client(&proto::PurgeClientRequest {
+                client_id: "bob".into(),
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(response.messages, 2);
+        assert_eq!(response.bytes > 0, true);
+        assert_eq!(response.held, 0);
+
+        // The counterpart's copies are gone too
+        let response = switchroom
+            .handle_get_messages(&proto::GetMessagesRequest {
+                client_id: "alice".into(),
+                sketch: "".into(),
+                salt: "".into(),
+                cursor: "".into(),
+                since: None,
+                until: None,
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(response.messages.len(), 0);
+    }
+
+    #[test]
+    fn get_messages_since() {
+        let switchroom = make_switchroom();
+
+        let old = switchroom
+            .handle_send_message(&make_message("old", "alice", "bob"))
+            .wait()
+            .unwrap();
+        let mut since = old.received_at.clone().unwrap();
+        since.nanos += 1;
+        let new = switchroom
+            .handle_send_message(&make_message("new", "alice", "bob"))
+            .wait()
+            .unwrap();
+
+        let response = switchroom
+            .handle_get_messages(&proto::GetMessagesRequest {
+                client_id: "bob".into(),
+                sketch: "".into(),
+                salt: "".into(),
+                cursor: "".into(),
+                since: Some(since),
+                until: None,
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(response.messages, vec![new]);
+    }
+
+    #[test]
+    fn subscribe_messages() {
+        let switchroom = make_switchroom();
+
+        let request = proto::SubscribeMessagesRequest {
+            client_id: "bob".into(),
+            since: None,
+        };
+        let mut messages = switchroom
+            .handle_subscribe_messages(&request)
+            .unwrap()
+            .wait();
+
+        // Sent before the first read
+        let first = switchroom
+            .handle_send_message(&make_message("hash 1", "alice", "bob"))
+            .wait()
+            .unwrap();
+        assert_eq!(messages.next().unwrap().unwrap(), first);
+
+        // Sent while the stream waits for the mailbox to change
+        let sender = switchroom.clone();
+        let sent = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            sender
+                .handle_send_message(&make_message("hash 2", "alice", "bob"))
+                .wait()
+                .unwrap()
+        });
+        assert_eq!(messages.next().unwrap().unwrap(), sent.join().unwrap());
+
+        let request = proto::SubscribeMessagesRequest {
+            client_id: "".into(),
+            since: None,
+        };
+        assert_eq!(
+            switchroom.handle_subscribe_messages(&request).is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn sync() {
+        use futures::sync::mpsc;
+        use switchroom_grpc::proto::sync_request::Request as SyncRequest;
+        use switchroom_grpc::proto::sync_response::Response as SyncResponse;
+
+        let switchroom = make_switchroom();
+        let old = switchroom
+            .handle_send_message(&make_message("hash 1", "alice", "bob"))
+            .wait()
+            .unwrap();
+
+        let (sender, receiver) = mpsc::unbounded();
+        let requests = receiver.map_err(|()| RequestError::StreamError {
+            err: "closed".to_string(),
+        });
+        let start = proto::SyncStart {
+            client_id: "bob".into(),
+            sketch: "".into(),
+            salt: "".into(),
+        };
+        sender
+            .unbounded_send(proto::SyncRequest {
+                request: Some(SyncRequest::Start(start)),
+            })
+            .unwrap();
+        let mut responses = switchroom.handle_sync(requests).wait().unwrap().wait();
+        let mut next = || responses.next().unwrap().unwrap().response.unwrap();
+
+        // The backlog, then the new messages
+        assert_eq!(next(), SyncResponse::Message(old));
+        assert_eq!(next(), SyncResponse::CaughtUp(proto::SyncCaughtUp {}));
+
+        // The client already has "hash 2"
+        sender
+            .unbounded_send(proto::SyncRequest {
+                request: Some(SyncRequest::Ack(proto::SyncAck {
+                    hashes: vec![make_hash("hash 2")],
+                })),
+            })
+            .unwrap();
+        let sender = switchroom.clone();
+        let sent = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            for hash in &["hash 2", "hash 3"] {
+                sender
+                    .handle_send_message(&make_message(hash, "alice", "bob"))
+                    .wait()
+                    .unwrap();
+            }
+        });
+        match next() {
+            SyncResponse::Message(message) => assert_eq!(message.hash, make_hash("hash 3")),
+            other => panic!("expected a message, got {:?}", other),
+        }
+        sent.join().unwrap();
+    }
+
+    #[test]
+    fn sync_rejects_acks() {
+        use futures::sync::mpsc;
+        use switchroom_grpc::proto::sync_request::Request as SyncRequest;
+
+        let switchroom = make_switchroom().with_limits(config::Limits {
+            max_sync_skipped: 2,
+            ..config::Limits::default()
+        });
+        let sync = |hashes: Vec<Vec<u8>>| {
+            let (sender, receiver) = mpsc::unbounded();
+            let requests = receiver.map_err(|()| RequestError::StreamError {
+                err: "closed".to_string(),
+            });
+            let start = proto::SyncStart {
+                client_id: "bob".into(),
+                sketch: "".into(),
+                salt: "".into(),
+            };
+            for request in vec![
+                SyncRequest::Start(start),
+                SyncRequest::Ack(proto::SyncAck { hashes }),
+            ] {
+                sender
+                    .unbounded_send(proto::SyncRequest {
+                        request: Some(request),
+                    })
+                    .unwrap();
+            }
+            // Only the ack can end the stream, since no message is sent
+            let responses = switchroom.handle_sync(requests).wait().unwrap();
+            responses
+                .wait()
+                .find(|response| response.is_err())
+                .unwrap()
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            sync(vec![b"short".to_vec()]),
+            "Bad arguments specified for request: \"acked hash must be 32 bytes, got 5\""
+        );
+        assert_eq!(
+            sync(vec![make_hash("1"), make_hash("2"), make_hash("3")]),
+            "Bad arguments specified for request: \"over 2 hashes acked and not yet passed\""
+        );
+    }
+
+    #[test]
+    fn subscription_trims_skipped() {
+        let at = |seconds| proto::Timestamp { seconds, nanos: 0 };
+        let skip: SkipSet = Arc::new(Mutex::new(HashMap::new()));
+        skip.lock().unwrap().insert(make_hash("old"), at(1_000));
+        skip.lock().unwrap().insert(make_hash("new"), at(1_100));
+        let mut subscription =
+            Subscription::new(Arc::new(MemoryDB::new(30)), "bob", at(900), skip.clone());
+
+        let message = proto::Message {
+            received_at: Some(at(1_050)),
+            ..make_message("hash 1", "alice", "bob")
+        };
+        subscription.record(storage::MailboxUpdates {
+            page: storage::MessagePage {
+                messages: vec![message],
+                cursor: None,
+            },
+            token: None,
+        });
+
+        // Nothing received before `since` is read again
+        assert_eq!(subscription.since, at(1_050 - SUBSCRIBE_LOOKBACK_SECONDS));
+        let skipped: Vec<_> = skip.lock().unwrap().keys().cloned().collect();
+        assert_eq!(skipped, vec![make_hash("new")]);
+    }
+
+    #[test]
+    fn error_status() {
+        use prost::Message;
+        use switchroom_grpc::proto::error_details::Reason;
+        use switchroom_grpc::tower_grpc::Code;
+
+        let switchroom = make_switchroom();
+        switchroom
+            .handle_send_message(&make_message("hash 1", "alice", "bob"))
+            .wait()
+            .unwrap();
+        let details = |status: &Status| proto::ErrorDetails::decode(status.details()).unwrap();
+
+        let missing = switchroom
+            .handle_delete_message(&proto::DeleteMessageRequest {
+                client_id: "bob".into(),
+                hash: make_hash("hash 2"),
+            })
+            .wait()
+            .unwrap_err()
+            .to_status();
+        assert_eq!(missing.code(), Code::NotFound);
+        assert_eq!(details(&missing).reason, Reason::NotFound as i32);
+
+        let forged = switchroom
+            .handle_send_message(&make_message("hash 1", "mallory", "bob"))
+            .wait()
+            .unwrap_err()
+            .to_status();
+        assert_eq!(forged.code(), Code::AlreadyExists);
+        assert_eq!(details(&forged).reason, Reason::HashConflict as i32);
+        assert_eq!(details(&forged).retryable, false);
+
+        let throttled = RequestError::from(storage::StorageError::Throttled {
+            err: "tag_throttled".to_string(),
+        })
+        .to_status();
+        assert_eq!(throttled.code(), Code::ResourceExhausted);
+        assert_eq!(details(&throttled).retryable, true);
+    }
+
+    #[test]
+    fn send_invalid_message() {
+        use switchroom_grpc::tower_grpc::Code;
+
+        let switchroom = make_switchroom().with_limits(config::Limits {
+            max_body_bytes: 4,
+            ..config::Limits::default()
+        });
+
+        let mut message = make_message("hash", "alice", "bob");
+        message.hash = b"hash".to_vec();
+        let err = switchroom.handle_send_message(&message).wait().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid message: hash must be 32 bytes, got 4"
+        );
+        assert_eq!(err.to_status().code(), Code::InvalidArgument);
+
+        let message = make_message("hash", "alice", "bob");
+        let err = switchroom.handle_send_message(&message).wait().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid message: body is 8 bytes, over the limit of 4"
+        );
+
+        // Nothing was stored
+        let response = switchroom
+            .handle_get_messages(&proto::GetMessagesRequest {
+                client_id: "bob".into(),
+                sketch: "".into(),
+                salt: "".into(),
+                cursor: "".into(),
+                since: None,
+                until: None,
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(response.messages.is_empty(), true);
+    }
 }
@@ -1,11 +1,25 @@
+use crate::config;
 use crate::metrics;
 
 use foundationdb::tuple::{Decode, Encode, Result};
 use foundationdb::{self, *};
 use futures::Future;
+use futures_cpupool::CpuPool;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use switchroom_grpc::proto;
+use switchroom_grpc::proto::blob_value::Codec;
 
-#[derive(Debug, Fail)]
+use self::retention::Retention;
+
+pub mod embedded;
+pub mod keyring;
+pub mod local;
+mod mailbox;
+pub mod memory;
+pub mod retention;
+
+#[derive(Clone, Debug, Fail)]
 pub enum StorageError {
     #[fail(display = "unable to encode message: {:?}", err)]
     EncodingFailure { err: String },
@@ -13,13 +27,55 @@ pub enum StorageError {
     DecodingFailure { err: String },
     #[fail(display = "Fdb error: {:?}", err)]
     FdbError { err: String },
+    #[fail(display = "Embedded store error: {:?}", err)]
+    EmbeddedError { err: String },
+    #[fail(display = "cursor does not belong to this mailbox")]
+    InvalidCursor,
+    #[fail(display = "a different message with this hash already exists")]
+    HashConflict,
+    #[fail(display = "Keyring error: {:?}", err)]
+    KeyringError { err: String },
+    #[fail(display = "client {:?} is under legal hold", client_id)]
+    LegalHold { client_id: String },
+    #[fail(display = "Schema version mismatch: {}", err)]
+    SchemaMismatch { err: String },
+    #[fail(display = "storage is unavailable: {:?}", err)]
+    Unavailable { err: String },
+    #[fail(display = "transaction conflicted with another: {:?}", err)]
+    Conflict { err: String },
+    #[fail(display = "transaction timed out: {:?}", err)]
+    Timeout { err: String },
+    #[fail(display = "transaction was throttled: {:?}", err)]
+    Throttled { err: String },
+    #[fail(display = "transaction is too large: {:?}", err)]
+    TooLarge { err: String },
+    #[fail(display = "version {} is too old to read: {:?}", version, err)]
+    VersionTooOld { version: i64, err: String },
+}
+
+/// Sort Fdb errors by what the caller can do about them. The codes are
+/// listed at https://apple.github.io/foundationdb/api-error-codes.html
+fn fdb_error(err: &foundationdb::Error) -> StorageError {
+    let message = err.to_string();
+    match err.code() {
+        // transaction_too_old, not_committed, commit_unknown_result
+        1007 | 1020 | 1021 => StorageError::Conflict { err: message },
+        // timed_out, transaction_timed_out
+        1004 | 1031 => StorageError::Timeout { err: message },
+        // future_version, process_behind, database_locked,
+        // cluster_version_changed
+        1009 | 1037 | 1038 | 1039 => StorageError::Unavailable { err: message },
+        // too_many_watches, batch_transaction_throttled, tag_throttled
+        1032 | 1051 | 1213 => StorageError::Throttled { err: message },
+        // transaction_too_large, key_too_large, value_too_large
+        2101 | 2102 | 2103 => StorageError::TooLarge { err: message },
+        _ => StorageError::FdbError { err: message },
+    }
 }
 
 impl From<foundationdb::Error> for StorageError {
     fn from(err: foundationdb::Error) -> StorageError {
-        StorageError::FdbError {
-            err: err.to_string(),
-        }
+        fdb_error(&err)
     }
 }
 
@@ -41,9 +97,7 @@ impl From<prost::DecodeError> for StorageError {
 
 impl From<(foundationdb::transaction::RangeOption, foundationdb::Error)> for StorageError {
     fn from(err: (foundationdb::transaction::RangeOption, foundationdb::Error)) -> StorageError {
-        StorageError::FdbError {
-            err: err.1.to_string(),
-        }
+        fdb_error(&err.1)
     }
 }
 
@@ -55,43 +109,1066 @@ impl From<foundationdb::tuple::Error> for StorageError {
     }
 }
 
+pub type StorageFuture<T> = Box<dyn Future<Item = T, Error = StorageError> + Send>;
+
+/// Filter applied to message hashes when reading a mailbox. Messages for
+/// which the filter returns `false` are skipped.
+pub type HashFilter = Box<dyn Fn(&[u8]) -> bool + Send>;
+
+/// One page of a client's mailbox.
+#[derive(Debug)]
+pub struct MessagePage {
+    pub messages: Vec<proto::Message>,
+    /// Set when the page was cut short by `RESPONSE_SIZE_LIMIT`. This is the
+    /// key of the last mailbox entry read, and the next page starts after it.
+    pub cursor: Option<Vec<u8>>,
+}
+
+/// The result of `get_mailbox_updates`.
+#[derive(Debug)]
+pub struct MailboxUpdates {
+    pub page: MessagePage,
+    /// The mailbox's notification token when the page was read. Pass it to
+    /// `watch_mailbox` to wait for the next message.
+    pub token: Option<Vec<u8>>,
+}
+
+/// Bounds on `received_at` for mailbox reads. `since` is inclusive and
+/// `until` is exclusive; a missing bound leaves that side open.
+#[derive(Clone, Debug, Default)]
+pub struct TimeRange {
+    pub since: Option<proto::Timestamp>,
+    pub until: Option<proto::Timestamp>,
+}
+
+/// The outcome of `insert_message`.
+#[derive(Debug, PartialEq)]
+pub struct InsertedMessage {
+    /// The message as stored. For a duplicate this is the original copy,
+    /// with its original `received_at`.
+    pub message: proto::Message,
+    /// Set when the recipient already had a message with this hash, in which
+    /// case nothing was written.
+    pub duplicate: bool,
+}
+
+/// The outcome for one message of an `insert_messages` call. Only
+/// `HashConflict` rejects a single message; any other error fails the call.
+pub type InsertResult = std::result::Result<InsertedMessage, StorageError>;
+
+/// The outcome of one `clear_expired` batch.
+#[derive(Debug, Default, PartialEq)]
+pub struct ClearedExpired {
+    /// Expired entries removed.
+    pub cleared: usize,
+    /// Expired entries kept because their client is under legal hold, by
+    /// client ID.
+    pub held: BTreeMap<String, usize>,
+}
+
+/// Raw key-value pairs read by `backup_keys`.
+#[derive(Debug, PartialEq)]
+pub struct KeyBatch {
+    pub kvs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Pass back in to read the next batch. `None` after the last one.
+    pub cursor: Option<Vec<u8>>,
+    /// The version every batch of the backup must read at.
+    pub version: i64,
+}
+
+/// The outcome of one `purge_client` batch.
+#[derive(Debug, Default, PartialEq)]
+pub struct PurgedMessages {
+    /// Messages removed from both mailboxes.
+    pub messages: usize,
+    /// The encoded size of those messages, before compression or
+    /// encryption.
+    pub bytes: usize,
+    /// Messages removed from the client's mailbox only, because the
+    /// counterpart is under legal hold.
+    pub held: usize,
+}
+
+/// Kinds of inconsistency found by `check_integrity`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ProblemKind {
+    /// A blob's chunks are missing, repeated or don't add up to its
+    /// `blob_length`.
+    IncompleteBlob,
+    /// A complete blob which doesn't decode to the message it's stored for.
+    UndecodableBlob,
+    /// A stored body which neither participant's mailbox references.
+    OrphanedBody,
+    /// A body referenced from only one participant's mailbox. This is
+    /// expected when the other entry was purged or expired while this one
+    /// was under legal hold, so it's never repaired.
+    UnpairedEntry,
+    /// A mailbox entry whose body isn't stored.
+    DanglingEntry,
+    /// A mailbox entry whose value isn't the other participant, or which is
+    /// in the mailbox of a client who isn't a participant at all.
+    MismatchedEntry,
+    /// An index entry with no mailbox entry.
+    OrphanedIndexEntry,
+    /// An expiry key with no mailbox entry.
+    OrphanedExpiryKey,
+    /// A key which doesn't decode. Never repaired.
+    UndecodableKey,
+}
+
+/// One inconsistency found by `check_integrity`.
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub detail: String,
+    /// Set when the keys involved were fixed or removed.
+    pub repaired: bool,
+}
+
+/// The outcome of one `check_integrity` batch.
+#[derive(Debug, Default, PartialEq)]
+pub struct IntegrityReport {
+    pub keys_checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// A backend capable of storing and serving messages.
+pub trait MessageStore: Send + Sync {
+    /// Store a message body once, and reference it from both the sender's
+    /// and the recipient's mailbox. Inserting a hash the recipient already
+    /// has is a no-op, so clients can safely retry.
+    fn insert_message(&self, message: proto::Message) -> StorageFuture<InsertedMessage>;
+
+    /// Insert each of `messages` as `insert_message` would, in as few
+    /// transactions as `INSERT_BATCH_SIZE_LIMIT` allows, returning a result
+    /// for each in order. The transactions are committed one after another,
+    /// and each message of one which fails gets its error as its result.
+    fn insert_messages(&self, messages: Vec<proto::Message>) -> StorageFuture<Vec<InsertResult>>;
+
+    /// Fetch the unexpired messages to and from `client_id` which pass
+    /// `filter`, up to `RESPONSE_SIZE_LIMIT` bytes. When `cursor` is set,
+    /// reading resumes after the key it points at.
+    fn get_messages_for(
+        &self,
+        client_id: &str,
+        cursor: Option<Vec<u8>>,
+        filter: HashFilter,
+    ) -> StorageFuture<MessagePage>;
+
+    /// Like `get_messages_for`, but only returns messages received within
+    /// `range`. Reads go through the received-time index, so only the blobs
+    /// inside the range are fetched.
+    fn get_messages_between(
+        &self,
+        client_id: &str,
+        range: TimeRange,
+        cursor: Option<Vec<u8>>,
+        filter: HashFilter,
+    ) -> StorageFuture<MessagePage>;
+
+    /// Like `get_messages_between` from `since` on, but also returns the
+    /// mailbox's notification token, read in the same transaction.
+    fn get_mailbox_updates(
+        &self,
+        client_id: &str,
+        since: proto::Timestamp,
+        filter: HashFilter,
+    ) -> StorageFuture<MailboxUpdates>;
+
+    /// Resolve once a message has been inserted for `client_id` since its
+    /// notification token was `token`, which may already be the case. This
+    /// can also resolve without a new message, so check again afterwards.
+    /// Fails if the backend can't watch the mailbox.
+    fn watch_mailbox(&self, client_id: &str, token: Option<Vec<u8>>) -> StorageFuture<()>;
+
+    /// Delete the message with `hash` from `client_id`'s mailbox, along with
+    /// the counterpart's entry, its body, its index entries and its expiry
+    /// keys. Only the sender or the recipient can find the message, so anyone
+    /// else gets `None` back, as does a caller asking for a missing message.
+    fn delete_message(
+        &self,
+        client_id: &str,
+        hash: Vec<u8>,
+    ) -> StorageFuture<Option<proto::Message>>;
+
+    /// Check up to `limit` mailbox entries whose expiry date has passed,
+    /// removing them and any body no longer referenced by a mailbox, unless
+    /// their client is under legal hold. Each call commits on its own, so
+    /// call it again with the returned cursor to clear a large backlog, until
+    /// the cursor is `None`.
+    fn clear_expired(
+        &self,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<(ClearedExpired, Option<Vec<u8>>)>;
+
+    /// Place a legal hold on `hold.client_id`, which keeps their messages past
+    /// expiry and refuses to delete them until it's removed.
+    fn add_legal_hold(&self, hold: proto::LegalHold) -> StorageFuture<()>;
+
+    /// Lift the legal hold on `client_id`, returning whether there was one.
+    fn remove_legal_hold(&self, client_id: &str) -> StorageFuture<bool>;
+
+    fn list_legal_holds(&self) -> StorageFuture<Vec<proto::LegalHold>>;
+
+    /// Remove up to `limit` messages from `client_id`'s mailbox, and their
+    /// copies in the counterparts' mailboxes, such as when an account is
+    /// deleted. Each call commits on its own, so call it again while it
+    /// returns `true` to purge a large mailbox. Fails if the client is under
+    /// legal hold.
+    fn purge_client(&self, client_id: &str, limit: usize) -> StorageFuture<(PurgedMessages, bool)>;
+
+    /// Fetch up to `limit` messages from `client_id`'s mailbox for an export,
+    /// however large they are. Messages which have expired but haven't been
+    /// cleared yet are included when `include_expired` is set. Pass the
+    /// returned cursor back in to continue.
+    fn export_messages(
+        &self,
+        client_id: &str,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        include_expired: bool,
+    ) -> StorageFuture<MessagePage>;
+
+    /// Read up to `limit` raw key-value pairs for a backup, starting after
+    /// `cursor`. Pass the version returned by the first batch to every later
+    /// one so the whole backup reads a single version. Fdb only serves reads
+    /// a few seconds old, so a batch read at a version which has fallen out
+    /// of that window fails with `VersionTooOld`, without being retried.
+    fn backup_keys(
+        &self,
+        version: Option<i64>,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<KeyBatch>;
+
+    /// Write raw key-value pairs read from a backup.
+    fn restore_keys(&self, kvs: Vec<(Vec<u8>, Vec<u8>)>) -> StorageFuture<()>;
+
+    /// Whether nothing at all is stored.
+    fn is_empty(&self) -> StorageFuture<bool>;
+
+    /// The schema version of the stored data, which is 0 for data written
+    /// before versions were recorded. See `MIGRATIONS`.
+    fn schema_version(&self) -> StorageFuture<i64>;
+
+    /// Run up to `limit` items of the migration to `version`, which must be
+    /// the version after the stored one. The stored version is bumped in the
+    /// same transaction as the last batch. Returns the number of items
+    /// migrated, and a cursor to pass back in if there may be more.
+    fn run_migration(
+        &self,
+        version: i64,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<(usize, Option<Vec<u8>>)>;
+
+    /// Check up to `limit` keys of the stored bodies, mailbox entries, index
+    /// entries and expiry keys against each other, repairing what can be
+    /// repaired when `repair` is set. Returns what was found, and a cursor to
+    /// pass back in if there may be more to check.
+    fn check_integrity(
+        &self,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        repair: bool,
+    ) -> StorageFuture<(IntegrityReport, Option<Vec<u8>>)>;
+
+    /// Check up to `limit` stored bodies, re-encrypting any which aren't
+    /// encrypted with the keyring's active key, such as after a key rotation.
+    /// Returns the number of bodies rewritten, and a cursor to pass back in if
+    /// there may be more.
+    fn reencrypt_blobs(
+        &self,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<(usize, Option<Vec<u8>>)>;
+
+    /// Convert up to `limit` mailbox entries written in the old layout, where
+    /// each mailbox held its own copy of the blob, into references to a
+    /// single shared body. Returns the number converted, and a cursor to pass
+    /// back in if there may be more.
+    fn migrate_legacy_blobs(
+        &self,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<(usize, Option<Vec<u8>>)>;
+}
+
+/// The key-value operations a backend offers inside a transaction. Reads
+/// observe the transaction's own writes.
+pub trait StorageTransaction {
+    fn get(&self, key: &[u8]) -> std::result::Result<Option<Vec<u8>>, StorageError>;
+
+    /// Visit every key in `[begin, end)` in order, until `f` returns false.
+    fn scan(
+        &self,
+        begin: &[u8],
+        end: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> std::result::Result<(), StorageError>;
+
+    fn set(&self, key: &[u8], value: &[u8]);
+
+    fn clear(&self, key: &[u8]);
+
+    fn clear_range(&self, begin: &[u8], end: &[u8]);
+
+    /// The version this transaction reads at. Backends without versioned
+    /// reads, whose transactions already see a single point in time, keep
+    /// the default.
+    fn read_version(&self) -> std::result::Result<i64, StorageError> {
+        Ok(0)
+    }
+
+    /// Read at `version`, which must be recent, instead of the latest
+    /// version. Must be called before any reads.
+    fn set_read_version(&self, _version: i64) {}
+}
+
+/// A backend which can run the mailbox operations in `mailbox` inside its
+/// own transactions. Every such backend is a `MessageStore`. Clones share the
+/// same store, so an operation spanning several transactions can keep one.
+pub trait TransactionalStore: Clone + Send + Sync + 'static {
+    /// Run `f` and commit its writes if it succeeds. `f` may be called more
+    /// than once if the transaction has to be retried. Backends run `f` on a
+    /// thread of their own, since it blocks on every read, and the returned
+    /// future resolves once it's committed.
+    fn transact<T, F>(&self, f: F) -> StorageFuture<T>
+    where
+        T: Send + 'static,
+        F: FnMut(&dyn StorageTransaction) -> std::result::Result<T, StorageError> + Send + 'static;
+
+    /// Resolve once `key` no longer holds `value`, committed by any
+    /// transaction, or fail if the watch does. Waiting must not hold up the
+    /// backend's threads.
+    fn watch(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> StorageFuture<()>;
+
+    fn retention(&self) -> Retention;
+
+    fn encoding(&self) -> BlobEncoding;
+}
+
+impl<S: TransactionalStore> MessageStore for S {
+    fn insert_message(&self, message: proto::Message) -> StorageFuture<InsertedMessage> {
+        let retention = self.retention();
+        let encoding = self.encoding();
+
+        self.transact(move |trx| mailbox::insert_message(trx, &message, &retention, &encoding))
+    }
+
+    fn insert_messages(&self, messages: Vec<proto::Message>) -> StorageFuture<Vec<InsertResult>> {
+        use futures::Stream;
+
+        let store = self.clone();
+        let batches = size_batches(messages, INSERT_BATCH_SIZE_LIMIT);
+        let results = futures::stream::iter_ok(batches).and_then(move |batch| {
+            let retention = store.retention();
+            let encoding = store.encoding();
+            let count = batch.len();
+            store
+                .transact(move |trx| mailbox::insert_messages(trx, &batch, &retention, &encoding))
+                .then(move |results| -> std::result::Result<_, StorageError> {
+                    match results {
+                        Ok(results) => Ok(results),
+                        Err(err) => Ok((0..count).map(|_| Err(err.clone())).collect()),
+                    }
+                })
+        });
+
+        Box::new(
+            results
+                .collect()
+                .map(|results| results.into_iter().flatten().collect()),
+        )
+    }
+
+    fn get_messages_for(
+        &self,
+        client_id: &str,
+        cursor: Option<Vec<u8>>,
+        filter: HashFilter,
+    ) -> StorageFuture<MessagePage> {
+        let client_id = client_id.to_string();
+        let retention = self.retention();
+        let encoding = self.encoding();
+
+        self.transact(move |trx| {
+            mailbox::get_messages_for(
+                trx,
+                &client_id,
+                cursor.clone(),
+                &*filter,
+                &retention,
+                &encoding,
+            )
+        })
+    }
+
+    fn get_messages_between(
+        &self,
+        client_id: &str,
+        range: TimeRange,
+        cursor: Option<Vec<u8>>,
+        filter: HashFilter,
+    ) -> StorageFuture<MessagePage> {
+        let client_id = client_id.to_string();
+        let retention = self.retention();
+        let encoding = self.encoding();
+
+        self.transact(move |trx| {
+            mailbox::get_messages_between(
+                trx,
+                &client_id,
+                &range,
+                cursor.clone(),
+                &*filter,
+                &retention,
+                &encoding,
+            )
+        })
+    }
+
+    fn get_mailbox_updates(
+        &self,
+        client_id: &str,
+        since: proto::Timestamp,
+        filter: HashFilter,
+    ) -> StorageFuture<MailboxUpdates> {
+        let client_id = client_id.to_string();
+        let retention = self.retention();
+        let encoding = self.encoding();
+
+        self.transact(move |trx| {
+            mailbox::get_mailbox_updates(
+                trx,
+                &client_id,
+                since.clone(),
+                &*filter,
+                &retention,
+                &encoding,
+            )
+        })
+    }
+
+    fn watch_mailbox(&self, client_id: &str, token: Option<Vec<u8>>) -> StorageFuture<()> {
+        self.watch(mailbox::notify_key(client_id), token)
+    }
+
+    fn delete_message(
+        &self,
+        client_id: &str,
+        hash: Vec<u8>,
+    ) -> StorageFuture<Option<proto::Message>> {
+        let client_id = client_id.to_string();
+        let retention = self.retention();
+        let encoding = self.encoding();
+
+        self.transact(move |trx| {
+            mailbox::delete_message(trx, &client_id, &hash, &retention, &encoding)
+        })
+    }
+
+    fn clear_expired(
+        &self,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<(ClearedExpired, Option<Vec<u8>>)> {
+        let expiry = to_integer_date(chrono::Utc::now().date());
+        // Expiry keys written before per-recipient retention are filed under
+        // the received date, and were written for the default period
+        let legacy_expiry = to_integer_date(expiry_time(self.retention().default_days()).date());
+
+        self.transact(move |trx| {
+            mailbox::clear_expired(trx, expiry, legacy_expiry, cursor.clone(), limit)
+        })
+    }
+
+    fn add_legal_hold(&self, hold: proto::LegalHold) -> StorageFuture<()> {
+        self.transact(move |trx| mailbox::add_legal_hold(trx, &hold))
+    }
+
+    fn remove_legal_hold(&self, client_id: &str) -> StorageFuture<bool> {
+        let client_id = client_id.to_string();
+
+        self.transact(move |trx| mailbox::remove_legal_hold(trx, &client_id))
+    }
+
+    fn list_legal_holds(&self) -> StorageFuture<Vec<proto::LegalHold>> {
+        self.transact(|trx| mailbox::list_legal_holds(trx))
+    }
+
+    fn purge_client(&self, client_id: &str, limit: usize) -> StorageFuture<(PurgedMessages, bool)> {
+        let client_id = client_id.to_string();
+        let retention = self.retention();
+        let encoding = self.encoding();
+
+        self.transact(move |trx| {
+            mailbox::purge_client(trx, &client_id, limit, &retention, &encoding)
+        })
+    }
+
+    fn export_messages(
+        &self,
+        client_id: &str,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        include_expired: bool,
+    ) -> StorageFuture<MessagePage> {
+        let client_id = client_id.to_string();
+        let retention = self.retention();
+        let encoding = self.encoding();
+
+        self.transact(move |trx| {
+            mailbox::export_messages(
+                trx,
+                &client_id,
+                cursor.clone(),
+                limit,
+                include_expired,
+                &retention,
+                &encoding,
+            )
+        })
+    }
+
+    fn backup_keys(
+        &self,
+        version: Option<i64>,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<KeyBatch> {
+        let mut attempts = 0;
+        self.transact(move |trx| {
+            let is_pinned = version.is_some();
+            let version = match version {
+                Some(version) => {
+                    // Retrying would read at the same version, which only
+                    // gets older
+                    attempts += 1;
+                    if attempts > 1 {
+                        return Err(StorageError::VersionTooOld {
+                            version,
+                            err: "the transaction had to be retried".to_string(),
+                        });
+                    }
+                    trx.set_read_version(version);
+                    version
+                }
+                None => trx.read_version()?,
+            };
+            // Reads at a pinned version only conflict when it's too old
+            let pinned = |err| match err {
+                StorageError::Conflict { err } if is_pinned => {
+                    StorageError::VersionTooOld { version, err }
+                }
+                err => err,
+            };
+
+            let (begin, end) = KEYSPACE_RANGE;
+            let begin = match &cursor {
+                Some(cursor) => {
+                    // The smallest key greater than the cursor
+                    let mut begin = cursor.clone();
+                    begin.push(0);
+                    begin
+                }
+                None => begin.to_vec(),
+            };
+
+            let mut kvs = vec![];
+            let mut more = false;
+            trx.scan(&begin, end, &mut |key, value| {
+                if kvs.len() == limit {
+                    more = true;
+                    return false;
+                }
+                kvs.push((key.to_vec(), value.to_vec()));
+                true
+            })
+            .map_err(pinned)?;
+
+            let cursor = if more {
+                kvs.last().map(|(key, _value)| key.clone())
+            } else {
+                None
+            };
+            Ok(KeyBatch {
+                kvs,
+                cursor,
+                version,
+            })
+        })
+    }
+
+    fn restore_keys(&self, kvs: Vec<(Vec<u8>, Vec<u8>)>) -> StorageFuture<()> {
+        self.transact(move |trx| {
+            for (key, value) in &kvs {
+                trx.set(key, value);
+            }
+            Ok(())
+        })
+    }
+
+    fn is_empty(&self) -> StorageFuture<bool> {
+        self.transact(|trx| {
+            let (begin, end) = KEYSPACE_RANGE;
+            let mut empty = true;
+            trx.scan(begin, end, &mut |_key, _value| {
+                empty = false;
+                false
+            })?;
+            Ok(empty)
+        })
+    }
+
+    fn schema_version(&self) -> StorageFuture<i64> {
+        self.transact(|trx| mailbox::schema_version(trx))
+    }
+
+    fn run_migration(
+        &self,
+        version: i64,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<(usize, Option<Vec<u8>>)> {
+        let retention = self.retention();
+        let encoding = self.encoding();
+
+        self.transact(move |trx| {
+            let migration = match MIGRATIONS.iter().find(|m| m.version == version) {
+                Some(migration) => migration,
+                None => {
+                    return Err(StorageError::SchemaMismatch {
+                        err: format!("there's no migration to version {}", version),
+                    });
+                }
+            };
+            let current = mailbox::schema_version(trx)?;
+            if current != version - 1 {
+                return Err(StorageError::SchemaMismatch {
+                    err: format!("can't migrate from version {} to {}", current, version),
+                });
+            }
+
+            let (count, cursor) =
+                (migration.run)(trx, cursor.clone(), limit, &retention, &encoding)?;
+            if cursor.is_none() {
+                mailbox::set_schema_version(trx, version);
+            }
+            Ok((count, cursor))
+        })
+    }
+
+    fn check_integrity(
+        &self,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        repair: bool,
+    ) -> StorageFuture<(IntegrityReport, Option<Vec<u8>>)> {
+        let retention = self.retention();
+        let encoding = self.encoding();
+
+        self.transact(move |trx| {
+            mailbox::check_integrity(trx, cursor.clone(), limit, repair, &retention, &encoding)
+        })
+    }
+
+    fn migrate_legacy_blobs(
+        &self,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<(usize, Option<Vec<u8>>)> {
+        let encoding = self.encoding();
+
+        self.transact(move |trx| {
+            mailbox::migrate_legacy_blobs(trx, cursor.clone(), limit, &encoding)
+        })
+    }
+
+    fn reencrypt_blobs(
+        &self,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> StorageFuture<(usize, Option<Vec<u8>>)> {
+        let encoding = self.encoding();
+
+        self.transact(move |trx| mailbox::reencrypt_blobs(trx, cursor.clone(), limit, &encoding))
+    }
+}
+
+/// A change to the key layout, made in batches by `run_migration` while the
+/// service keeps running. Reads, deletes and expiry handle the layouts from
+/// before every migration, and writes always use the latest, so a migration
+/// only has to move old data forward.
+pub struct Migration {
+    /// The schema version once this migration has finished. Each migration
+    /// runs from the version before it.
+    pub version: i64,
+    pub description: &'static str,
+    run: MigrationFn,
+}
+
+/// Migrate up to `limit` items after `cursor`, returning the number migrated
+/// and a cursor if there may be more.
+type MigrationFn = fn(
+    &dyn StorageTransaction,
+    Option<Vec<u8>>,
+    usize,
+    &Retention,
+    &BlobEncoding,
+) -> std::result::Result<(usize, Option<Vec<u8>>), StorageError>;
+
+/// The schema version this build writes.
+pub const SCHEMA_VERSION: i64 = 2;
+
+/// Every migration, in order.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "move blobs held in each mailbox into shared bodies",
+        run: migrate_legacy_blobs,
+    },
+    Migration {
+        version: 2,
+        description: "refile expiry keys filed under the received date",
+        run: mailbox::migrate_legacy_expiry_keys,
+    },
+];
+
+fn migrate_legacy_blobs(
+    trx: &dyn StorageTransaction,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+    _retention: &Retention,
+    encoding: &BlobEncoding,
+) -> std::result::Result<(usize, Option<Vec<u8>>), StorageError> {
+    mailbox::migrate_legacy_blobs(trx, cursor, limit, encoding)
+}
+
+/// Bring the stored data up to `SCHEMA_VERSION`, running each outstanding
+/// migration in batches of `limit` items. Safe to run alongside the service,
+/// and to interrupt and run again. Returns the schema version reached.
+pub fn migrate(storage: &dyn MessageStore, limit: usize) -> std::result::Result<i64, StorageError> {
+    let mut version = storage.schema_version().wait()?;
+    if version > SCHEMA_VERSION {
+        return Err(StorageError::SchemaMismatch {
+            err: format!(
+                "stored data is at version {}, but this build only knows up to {}",
+                version, SCHEMA_VERSION
+            ),
+        });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        info!(
+            "Migrating to schema version {}: {}",
+            migration.version, migration.description
+        );
+        let mut cursor = None;
+        let mut total = 0;
+        loop {
+            let (count, next) = storage
+                .run_migration(migration.version, cursor, limit)
+                .wait()?;
+            total += count;
+            info!("Migrated {} items", total);
+
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        version = migration.version;
+    }
+    Ok(version)
+}
+
+/// Create the storage backend selected in the config.
+pub fn open(config: &config::Config) -> std::result::Result<Arc<dyn MessageStore>, StorageError> {
+    let keyring = match config.storage.keyring_path {
+        Some(ref path) => Some(Arc::new(keyring::Keyring::load(path)?)),
+        None => None,
+    };
+    let encoding = BlobEncoding {
+        codec: match config.storage.compression {
+            config::Compression::None => Codec::None,
+            config::Compression::Zstd => Codec::Zstd,
+        },
+        keyring,
+    };
+    let retention = Retention::new(config.message_expiry_days)
+        .with_client_days(config.retention.clients.clone());
+    let pool = CpuPool::new(config.storage.threads.unwrap_or(DEFAULT_THREADS));
+
+    Ok(match config.storage.backend {
+        config::StorageBackend::Fdb => {
+            if config.storage.keyspace.is_empty() {
+                warn!(
+                    "Keyspace is empty, so keys are at the root, overlapping every other keyspace"
+                );
+            }
+            let options = FdbOptions {
+                cluster_file: config.storage.cluster_file.clone(),
+                transaction_timeout_ms: config.storage.transaction_timeout_ms,
+                transaction_retry_limit: config.storage.transaction_retry_limit,
+                transaction_max_retry_delay_ms: config.storage.transaction_max_retry_delay_ms,
+            };
+            Arc::new(
+                DB::new(&options, config.message_expiry_days)?
+                    .with_keyspace(&config.storage.keyspace)
+                    .with_retention(retention)
+                    .with_encoding(encoding)
+                    .with_pool(pool),
+            )
+        }
+        config::StorageBackend::Embedded => {
+            let path = match config.storage.embedded_path {
+                Some(ref path) => path,
+                None => {
+                    return Err(StorageError::EmbeddedError {
+                        err: "embedded_path is required by the embedded backend".to_string(),
+                    })
+                }
+            };
+            Arc::new(
+                embedded::EmbeddedDB::open(path, config.message_expiry_days)?
+                    .with_retention(retention)
+                    .with_encoding(encoding)
+                    .with_pool(pool),
+            )
+        }
+        config::StorageBackend::Memory => Arc::new(
+            memory::MemoryDB::new(config.message_expiry_days)
+                .with_retention(retention)
+                .with_encoding(encoding)
+                .with_pool(pool),
+        ),
+    })
+}
+
+#[derive(Clone)]
 pub struct DB {
     db: foundationdb::Database,
-    expiry_days: i64,
+    /// Prepended to every key, so that several deployments can share a
+    /// cluster.
+    prefix: Vec<u8>,
+    retention: Retention,
+    encoding: BlobEncoding,
+    /// Runs transactions, which block on their reads.
+    pool: CpuPool,
 }
 
 const CHUNK_SIZE: usize = 10_000;
+/// Every key Switchroom writes. Fdb reserves keys from 0xff for itself.
+const KEYSPACE_RANGE: (&[u8], &[u8]) = (&[0x00], &[0xff]);
 const RESPONSE_SIZE_LIMIT: i64 = 10_485_760;
+/// Encoded message bytes per `insert_messages` transaction. Each body is also
+/// chunked and keyed several times over, so this stays well clear of Fdb's
+/// 10MB transaction limit.
+const INSERT_BATCH_SIZE_LIMIT: usize = 4_194_304;
+/// Items handled per transaction by the operations run in batches, such as
+/// `purge_client`, `reencrypt_blobs` and `run_migration`, which keeps each
+/// one well within Fdb's transaction size limit.
+pub const BATCH_SIZE: usize = 100;
+/// Threads in the transaction pool when none is configured. A transaction
+/// holds its thread while it waits on every read and on the commit, so the
+/// pool is sized for transactions in flight rather than for CPUs.
+pub const DEFAULT_THREADS: usize = 64;
+
+/// Split `messages` into runs of at most `limit` encoded bytes, keeping their
+/// order. A message over the limit gets a run of its own.
+fn size_batches(messages: Vec<proto::Message>, limit: usize) -> Vec<Vec<proto::Message>> {
+    use prost::Message;
+
+    let mut batches = vec![];
+    let mut batch = vec![];
+    let mut size = 0;
+    for message in messages {
+        let len = message.encoded_len();
+        if !batch.is_empty() && size + len > limit {
+            batches.push(std::mem::replace(&mut batch, vec![]));
+            size = 0;
+        }
+        size += len;
+        batch.push(message);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
 
 type BlobKey = (String, String, Vec<u8>, i64);
+type BodyKey = (String, Vec<u8>, i64);
+type RefKey = (String, String, Vec<u8>);
 type ExpKey = (String, i64, String, Vec<u8>);
+type TimeKey = (String, String, i64, i64, Vec<u8>);
 
-fn set_blob(trx: &Transaction, subspace: &Subspace, value: &[u8], expiry: i64) {
+/// Split `value` into encoded `BlobValue` chunks, keyed by the offset of the
+/// first byte in each chunk. Fields other than the length, chunk number and
+/// payload are copied from `header`.
+fn blob_chunks(value: &[u8], header: &proto::BlobValue) -> Vec<(i64, Vec<u8>)> {
     use prost::Message;
 
     let num_chunks = (value.len() + CHUNK_SIZE - 1) / CHUNK_SIZE;
     let chunk_size = (value.len() + num_chunks) / num_chunks;
 
-    for i in 0..num_chunks {
-        let start = i * chunk_size;
-        let end = if (i + 1) * chunk_size <= value.len() {
-            (i + 1) * chunk_size
+    (0..num_chunks)
+        .map(|i| {
+            let start = i * chunk_size;
+            let end = if (i + 1) * chunk_size <= value.len() {
+                (i + 1) * chunk_size
+            } else {
+                value.len()
+            };
+
+            let blob_value = proto::BlobValue {
+                blob_length: value.len() as i64,
+                blob_chunk: i as i64,
+                payload: value[start..end].into(),
+                ..header.clone()
+            };
+            let mut blob_value_buf = Vec::new();
+            blob_value
+                .encode(&mut blob_value_buf)
+                .expect("Failed to encode message");
+
+            (start as i64, blob_value_buf)
+        })
+        .collect()
+}
+
+/// Write `value`, the blob for the message with `hash`, under `subspace`.
+fn set_blob(
+    trx: &dyn StorageTransaction,
+    subspace: &Subspace,
+    value: &[u8],
+    hash: &[u8],
+    expiry: i64,
+    encoding: &BlobEncoding,
+) -> std::result::Result<(), StorageError> {
+    let (encoded, key_id) = encoding.encode(value, hash)?;
+    let header = proto::BlobValue {
+        expiry,
+        codec: encoding.codec as i32,
+        decoded_length: value.len() as i64,
+        key_id,
+        ..Default::default()
+    };
+    for (start, blob_value_buf) in blob_chunks(&encoded, &header) {
+        trx.set(&subspace.pack(start), &blob_value_buf);
+    }
+    Ok(())
+}
+
+/// How new blobs are encoded, along with the keys needed to read encrypted
+/// blobs.
+#[derive(Clone)]
+pub struct BlobEncoding {
+    pub codec: Codec,
+    /// Blobs are encrypted at rest when set.
+    pub keyring: Option<Arc<keyring::Keyring>>,
+}
+
+impl Default for BlobEncoding {
+    fn default() -> Self {
+        BlobEncoding {
+            codec: Codec::None,
+            keyring: None,
+        }
+    }
+}
+
+impl BlobEncoding {
+    /// Compress, then encrypt, the blob for the message with `hash`. Returns
+    /// the encoded blob and the ID of the key it was encrypted with, which is
+    /// empty when there's no keyring.
+    fn encode(
+        &self,
+        value: &[u8],
+        hash: &[u8],
+    ) -> std::result::Result<(Vec<u8>, String), StorageError> {
+        let compressed = compress(value, self.codec)?;
+        match self.keyring {
+            Some(ref keyring) => {
+                let (key_id, sealed) = keyring.encrypt(&compressed, hash)?;
+                Ok((sealed, key_id))
+            }
+            None => Ok((compressed, String::new())),
+        }
+    }
+
+    /// Reverse `encode`, using the codec and key ID recorded in the blob's
+    /// chunks.
+    fn decode(
+        &self,
+        value: Vec<u8>,
+        hash: &[u8],
+        codec: i32,
+        key_id: &str,
+    ) -> std::result::Result<Vec<u8>, StorageError> {
+        let value = if key_id.is_empty() {
+            value
         } else {
-            value.len()
+            match self.keyring {
+                Some(ref keyring) => keyring.decrypt(key_id, &value, hash)?,
+                None => {
+                    return Err(StorageError::KeyringError {
+                        err: format!(
+                            "blob is encrypted with {:?}, but there's no keyring",
+                            key_id
+                        ),
+                    });
+                }
+            }
         };
+        decompress(value, codec)
+    }
 
-        let blob_value = proto::BlobValue {
-            blob_length: value.len() as i64,
-            blob_chunk: i as i64,
-            expiry,
-            payload: value[start..end].into(),
-        };
-        let mut blob_value_buf = Vec::new();
-        blob_value
-            .encode(&mut blob_value_buf)
-            .expect("Failed to encode message");
+    /// Whether a blob encrypted with `key_id` should be rewritten to bring it
+    /// onto the active key.
+    fn is_stale(&self, key_id: &str) -> bool {
+        match self.keyring {
+            Some(ref keyring) => key_id != keyring.active_key(),
+            None => false,
+        }
+    }
+}
+
+fn compress(value: &[u8], codec: Codec) -> std::result::Result<Vec<u8>, StorageError> {
+    match codec {
+        Codec::None => Ok(value.to_vec()),
+        // Level 0 selects zstd's default level
+        Codec::Zstd => zstd::encode_all(value, 0).map_err(|err| StorageError::EncodingFailure {
+            err: err.to_string(),
+        }),
+    }
+}
+
+/// Decode a reassembled blob. `codec` comes from the blob's chunks, and may
+/// name a codec this build doesn't know about.
+fn decompress(value: Vec<u8>, codec: i32) -> std::result::Result<Vec<u8>, StorageError> {
+    match Codec::from_i32(codec) {
+        Some(Codec::None) => Ok(value),
+        Some(Codec::Zstd) => {
+            zstd::decode_all(&value[..]).map_err(|err| StorageError::DecodingFailure {
+                err: err.to_string(),
+            })
+        }
+        None => Err(StorageError::DecodingFailure {
+            err: format!("unknown blob codec {}", codec),
+        }),
+    }
+}
 
-        trx.set(&subspace.pack(start as i64), &blob_value_buf);
+/// Size of a blob's message once decoded. Chunks written before blobs could
+/// be compressed don't record it, but are stored as is.
+fn decoded_length(blob_value: &proto::BlobValue) -> i64 {
+    if blob_value.decoded_length > 0 {
+        blob_value.decoded_length
+    } else {
+        blob_value.blob_length
     }
 }
 
@@ -100,17 +1177,450 @@ fn to_integer_date(expiry: chrono::Date<chrono::Utc>) -> i64 {
     i64::from(expiry.year()) * 10_000 + i64::from(expiry.month()) * 100 + i64::from(expiry.day())
 }
 
+/// Messages received before this time have expired.
+fn expiry_time(expiry_days: i64) -> chrono::DateTime<chrono::Utc> {
+    chrono::Utc::now() - chrono::Duration::days(expiry_days)
+}
+
+fn received_at(message: &proto::Message) -> chrono::DateTime<chrono::Utc> {
+    use chrono::prelude::*;
+
+    let timestamp = message
+        .received_at
+        .as_ref()
+        .expect("Couldn't get timestamp");
+    DateTime::<Utc>::from_utc(
+        NaiveDateTime::from_timestamp(timestamp.seconds, timestamp.nanos as u32),
+        Utc,
+    )
+}
+
+/// The other participant in `message`, from `client_id`'s point of view.
+fn counterpart<'a>(message: &'a proto::Message, client_id: &str) -> &'a str {
+    if message.to == client_id {
+        &message.from
+    } else {
+        &message.to
+    }
+}
+
+/// Key of the entry in `client_id`'s mailbox referencing the body of `hash`.
+/// Its value is the counterpart's client ID.
+fn mailbox_key(client_id: &str, hash: &[u8]) -> Vec<u8> {
+    ("M", client_id, hash.to_vec()).to_vec()
+}
+
+/// The range covering `client_id`'s entry for `hash`, in either the current
+/// layout or the legacy one, where the blob chunks were stored under the
+/// entry itself.
+fn entry_range(client_id: &str, hash: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let begin = mailbox_key(client_id, hash);
+    let mut end = begin.clone();
+    end.push(0xff);
+    (begin, end)
+}
+
+/// The range holding the single stored copy of the body of `hash`.
+fn body_range(hash: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    Subspace::from(("B", hash.to_vec())).range()
+}
+
+/// A decoded key from a client's mailbox.
+enum MailboxKey {
+    /// A reference to a shared body.
+    Reference { client_id: String, hash: Vec<u8> },
+    /// A chunk of a blob stored in the mailbox itself, from before bodies
+    /// were shared.
+    LegacyChunk { client_id: String, hash: Vec<u8> },
+}
+
+fn decode_mailbox_key(key: &[u8]) -> Option<MailboxKey> {
+    let result: Result<BlobKey> = Decode::try_from(key);
+    if let Ok((_prefix, client_id, hash, _offset)) = result {
+        return Some(MailboxKey::LegacyChunk { client_id, hash });
+    }
+    let result: Result<RefKey> = Decode::try_from(key);
+    match result {
+        Ok((_prefix, client_id, hash)) => Some(MailboxKey::Reference { client_id, hash }),
+        Err(err) => {
+            error!("failed to decode mailbox key: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Key of the received-time index entry for `hash` in `client_id`'s mailbox.
+fn time_key(client_id: &str, timestamp: &proto::Timestamp, hash: &[u8]) -> Vec<u8> {
+    (
+        "T",
+        client_id,
+        timestamp.seconds,
+        i64::from(timestamp.nanos),
+        hash.to_vec(),
+    )
+        .to_vec()
+}
+
+/// The value stored under an expiry key: the message's received time, which
+/// locates its index entries.
+fn expiry_value(timestamp: &proto::Timestamp) -> Vec<u8> {
+    (timestamp.seconds, i64::from(timestamp.nanos)).to_vec()
+}
+
+/// The index entry to clear along with an expired message. Expiry keys
+/// written before the index existed have an empty value, and no index entry.
+fn expired_time_key(client_id: &str, hash: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+    if value.is_empty() {
+        return None;
+    }
+    let result: Result<(i64, i64)> = Decode::try_from(value);
+    match result {
+        Ok((seconds, nanos)) => Some(time_key(
+            client_id,
+            &proto::Timestamp {
+                seconds,
+                nanos: nanos as i32,
+            },
+            hash,
+        )),
+        Err(err) => {
+            error!("error decoding expiry value: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Keys written for a message, to be cleared together.
+struct MessageKeys {
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    keys: Vec<Vec<u8>>,
+}
+
+/// The mailbox entry, index entry and expiry keys written for `message` in
+/// `client_id`'s mailbox, including the expiry keys of the legacy layout.
+fn entry_keys(message: &proto::Message, client_id: &str, retention: &Retention) -> MessageKeys {
+    let timestamp = message
+        .received_at
+        .as_ref()
+        .expect("Couldn't get timestamp");
+    let expires = to_integer_date(retention.expires_at(message).date());
+    let received = to_integer_date(received_at(message).date());
+
+    MessageKeys {
+        ranges: vec![entry_range(client_id, &message.hash)],
+        keys: vec![
+            time_key(client_id, timestamp, &message.hash),
+            ("E", expires, client_id, message.hash.clone()).to_vec(),
+            ("R", received, client_id, message.hash.clone()).to_vec(),
+        ],
+    }
+}
+
+/// The body, and the entries of both participants, written for `message`.
+fn message_keys(message: &proto::Message, retention: &Retention) -> MessageKeys {
+    let mut message_keys = MessageKeys {
+        ranges: vec![body_range(&message.hash)],
+        keys: vec![],
+    };
+    for client_id in &[&message.to, &message.from] {
+        let entry_keys = entry_keys(message, client_id, retention);
+        message_keys.ranges.extend(entry_keys.ranges);
+        message_keys.keys.extend(entry_keys.keys);
+    }
+    message_keys
+}
+
+/// Collect the values of every key in `[begin, end)`.
+fn scan_values(
+    trx: &dyn StorageTransaction,
+    begin: &[u8],
+    end: &[u8],
+) -> std::result::Result<Vec<Vec<u8>>, StorageError> {
+    let mut values = vec![];
+    trx.scan(begin, end, &mut |_key, value| {
+        values.push(value.to_vec());
+        true
+    })?;
+    Ok(values)
+}
+
+/// Reassemble the message with `hash` from the values of its blob chunks,
+/// read in key order. Returns `None` if there were no chunks.
+fn read_blob(
+    values: &[Vec<u8>],
+    hash: &[u8],
+    encoding: &BlobEncoding,
+) -> std::result::Result<Option<proto::Message>, StorageError> {
+    use prost::Message;
+
+    if values.is_empty() {
+        return Ok(None);
+    }
+    let mut buf = Vec::new();
+    let mut header = proto::BlobValue::default();
+    for value in values {
+        let mut blob_value = proto::BlobValue::decode(value)?;
+        buf.append(&mut blob_value.payload);
+        header = blob_value;
+    }
+    let buf = encoding.decode(buf, hash, header.codec, &header.key_id)?;
+    Ok(Some(proto::Message::decode(&buf)?))
+}
+
+/// Decode an index entry, returning the message hash it points at.
+fn decode_time_key(key: &[u8]) -> Option<Vec<u8>> {
+    let result: Result<TimeKey> = Decode::try_from(key);
+    match result {
+        Ok((_prefix, _client_id, _seconds, _nanos, hash)) => Some(hash),
+        Err(err) => {
+            error!("failed to decode time key: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Move `begin` to just after `cursor`, which must lie within `subspace`.
+fn resume_from(
+    subspace: &Subspace,
+    begin: Vec<u8>,
+    cursor: Option<Vec<u8>>,
+) -> std::result::Result<Vec<u8>, StorageError> {
+    match cursor {
+        Some(mut cursor) => {
+            let (first, last) = subspace.range();
+            if cursor < first || cursor >= last {
+                return Err(StorageError::InvalidCursor);
+            }
+            // The smallest key greater than the cursor
+            cursor.push(0);
+            Ok(std::cmp::max(cursor, begin))
+        }
+        None => Ok(begin),
+    }
+}
+
+/// The key range holding `client_id`'s mailbox, starting just after `cursor`
+/// when resuming from a previous page.
+fn mailbox_range(
+    client_id: &str,
+    cursor: Option<Vec<u8>>,
+) -> std::result::Result<(Vec<u8>, Vec<u8>), StorageError> {
+    let subspace = Subspace::from(("M", client_id));
+    let (begin, end) = subspace.range();
+    Ok((resume_from(&subspace, begin, cursor)?, end))
+}
+
+/// The range of `client_id`'s received-time index covering `range`, starting
+/// just after `cursor` when resuming from a previous page.
+fn received_range(
+    client_id: &str,
+    range: &TimeRange,
+    cursor: Option<Vec<u8>>,
+) -> std::result::Result<(Vec<u8>, Vec<u8>), StorageError> {
+    let subspace = Subspace::from(("T", client_id));
+    let (begin, end) = subspace.range();
+    let begin = match range.since {
+        Some(ref since) => subspace.pack((since.seconds, i64::from(since.nanos))),
+        None => begin,
+    };
+    let end = match range.until {
+        Some(ref until) => subspace.pack((until.seconds, i64::from(until.nanos))),
+        None => end,
+    };
+    Ok((resume_from(&subspace, begin, cursor)?, end))
+}
+
+/// Reassembles messages from mailbox entries read in key order, applying the
+/// hash filter, the retention policy and `RESPONSE_SIZE_LIMIT`. Reference
+/// entries have their body fetched, legacy entries carry it in their chunks.
+struct BlobAssembler<'a> {
+    filter: &'a dyn Fn(&[u8]) -> bool,
+    retention: &'a Retention,
+    now: chrono::DateTime<chrono::Utc>,
+    encoding: &'a BlobEncoding,
+    size_limit: Option<i64>,
+    message_limit: Option<usize>,
+    include_expired: bool,
+    buf: Vec<u8>,
+    message_bytes: i64,
+    full: bool,
+    last_key: Vec<u8>,
+    messages: Vec<proto::Message>,
+}
+
+impl<'a> BlobAssembler<'a> {
+    fn new(
+        filter: &'a dyn Fn(&[u8]) -> bool,
+        retention: &'a Retention,
+        encoding: &'a BlobEncoding,
+    ) -> Self {
+        BlobAssembler {
+            filter,
+            retention,
+            now: chrono::Utc::now(),
+            encoding,
+            size_limit: Some(RESPONSE_SIZE_LIMIT),
+            message_limit: None,
+            include_expired: false,
+            buf: Vec::new(),
+            message_bytes: 0,
+            full: false,
+            last_key: vec![],
+            messages: vec![],
+        }
+    }
+
+    /// Collect up to `limit` messages whatever their size, instead of
+    /// stopping at `RESPONSE_SIZE_LIMIT`, optionally keeping expired ones.
+    fn for_export(mut self, limit: usize, include_expired: bool) -> Self {
+        self.size_limit = None;
+        self.message_limit = Some(limit);
+        self.include_expired = include_expired;
+        self
+    }
+
+    /// Feed the next mailbox key/value pair. Returns false once the response
+    /// is full, after which further entries are ignored.
+    fn push(
+        &mut self,
+        trx: &dyn StorageTransaction,
+        key: &[u8],
+        value: &[u8],
+    ) -> std::result::Result<bool, StorageError> {
+        if self.full {
+            return Ok(false);
+        }
+
+        match decode_mailbox_key(key) {
+            Some(MailboxKey::LegacyChunk { hash, .. }) => {
+                if (self.filter)(&hash) && !self.push_chunk(&hash, value) {
+                    return Ok(false);
+                }
+            }
+            Some(MailboxKey::Reference { hash, .. }) => {
+                if (self.filter)(&hash) {
+                    let (begin, end) = body_range(&hash);
+                    for chunk in scan_values(trx, &begin, &end)? {
+                        if !self.push_chunk(&hash, &chunk) {
+                            return Ok(false);
+                        }
+                    }
+                    if !self.buf.is_empty() {
+                        error!("incomplete body for message: {:?}", hash);
+                        self.buf.clear();
+                    }
+                }
+            }
+            None => (),
+        }
+        // Only resume from the end of a complete message
+        if self.buf.is_empty() {
+            self.last_key = key.to_vec();
+        }
+        Ok(true)
+    }
+
+    /// Whether a message of `message_length` bytes would take the response
+    /// past its limits.
+    fn is_over_limit(&self, message_length: i64) -> bool {
+        let over_size = match self.size_limit {
+            Some(limit) => self.message_bytes > 0 && message_length + self.message_bytes > limit,
+            None => false,
+        };
+        let over_count = match self.message_limit {
+            Some(limit) => self.messages.len() >= limit,
+            None => false,
+        };
+        over_size || over_count
+    }
+
+    /// Feed the next blob chunk of the message with `hash`. Returns false if
+    /// the message would exceed the response's limits.
+    fn push_chunk(&mut self, hash: &[u8], value: &[u8]) -> bool {
+        use prost::Message;
+
+        match proto::BlobValue::decode(value) {
+            Ok(mut blob_value) => {
+                // check if we've hit size limit, if so, stop here
+                let message_length = decoded_length(&blob_value);
+                if self.is_over_limit(message_length) {
+                    self.full = true;
+                    return false;
+                }
+                self.buf.append(&mut blob_value.payload);
+                if self.buf.len() == blob_value.blob_length as usize {
+                    let buf = std::mem::replace(&mut self.buf, Vec::new());
+                    let result = self
+                        .encoding
+                        .decode(buf, hash, blob_value.codec, &blob_value.key_id)
+                        .and_then(|buf| Ok(proto::Message::decode(&buf)?));
+                    match result {
+                        Ok(message) => {
+                            if self.include_expired
+                                || self.retention.expires_at(&message) > self.now
+                            {
+                                self.message_bytes += message_length;
+                                self.messages.push(message);
+                            }
+                        }
+                        Err(err) => {
+                            error!("failed to decode message: {:?}", err);
+                            metrics::MESSAGE_DECODE_FAILURE.inc();
+                        }
+                    }
+                }
+            }
+            Err(err) => error!("failed to decode blob value: {:?}", err),
+        }
+        true
+    }
+
+    fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// Make `key` the cursor position, for reads which walk an index rather
+    /// than the mailbox keys themselves.
+    fn resume_after(&mut self, key: &[u8]) {
+        self.last_key = key.to_vec();
+    }
+
+    fn into_page(self) -> MessagePage {
+        MessagePage {
+            messages: self.messages,
+            cursor: if self.full { Some(self.last_key) } else { None },
+        }
+    }
+}
+
+/// How to connect to Fdb, and the options every transaction runs with.
+/// Options left unset keep Fdb's defaults.
+#[derive(Clone, Debug, Default)]
+pub struct FdbOptions {
+    /// Cluster file to connect with, rather than Fdb's default one.
+    pub cluster_file: Option<String>,
+    /// Milliseconds before a transaction fails, including its retries.
+    /// Without a timeout, transactions wait for as long as the cluster is
+    /// unreachable.
+    pub transaction_timeout_ms: Option<i32>,
+    /// Times a transaction is retried before failing.
+    pub transaction_retry_limit: Option<i32>,
+    /// Longest delay between retries of a transaction, in milliseconds.
+    pub transaction_max_retry_delay_ms: Option<i32>,
+}
+
 impl DB {
-    pub fn new(expiry_days: i64) -> Self {
+    pub fn new(options: &FdbOptions, expiry_days: i64) -> std::result::Result<Self, StorageError> {
+        use foundationdb::options::DatabaseOption;
         use futures::future::*;
 
-        let network = foundationdb::init().expect("failed to initialize Fdb client");
+        let network = foundationdb::init()?;
 
         std::thread::spawn(move || {
             let error = network.run();
 
             if let Err(error) = error {
-                panic!("fdb_run_network: {}", error);
+                error!("fdb_run_network: {}", error);
             }
         });
 
@@ -118,393 +1628,572 @@ impl DB {
         network.wait();
 
         // work with Fdb
-        let db = Cluster::new(foundationdb::default_config_path())
+        let cluster_file = match options.cluster_file {
+            Some(ref path) => path.as_str(),
+            None => foundationdb::default_config_path(),
+        };
+        let db = Cluster::new(cluster_file)
             .and_then(|cluster| cluster.create_database())
-            .wait()
-            .expect("failed to create Cluster");
+            .wait()?;
+
+        if let Some(timeout) = options.transaction_timeout_ms {
+            db.set_option(DatabaseOption::TransactionTimeout(timeout))?;
+        }
+        if let Some(limit) = options.transaction_retry_limit {
+            db.set_option(DatabaseOption::TransactionRetryLimit(limit))?;
+        }
+        if let Some(delay) = options.transaction_max_retry_delay_ms {
+            db.set_option(DatabaseOption::TransactionMaxRetryDelay(delay))?;
+        }
 
-        DB { db, expiry_days }
+        Ok(DB {
+            db,
+            prefix: vec![],
+            retention: Retention::new(expiry_days),
+            encoding: BlobEncoding::default(),
+            pool: CpuPool::new(DEFAULT_THREADS),
+        })
     }
 
-    pub fn insert_message(
-        &self,
-        message: proto::Message,
-    ) -> Box<dyn Future<Item = proto::Message, Error = StorageError>> {
-        use chrono::prelude::*;
-        use prost::Message;
+    /// Keep every key under `path`, rather than at the root of the
+    /// cluster's keyspace. An empty path keeps the root.
+    pub fn with_keyspace(mut self, path: &[String]) -> Self {
+        self.prefix = keyspace_prefix(path);
+        self
+    }
 
-        self.db.transact(move |trx| {
-            let mut buf = Vec::new();
-            message.encode(&mut buf).expect("Failed to encode message");
+    /// Keep messages for the periods set by `retention`, rather than for
+    /// `expiry_days` whoever they're for.
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
 
-            let m_subspace = Subspace::from("M");
+    /// Encode newly written bodies with `encoding`, which must also be able
+    /// to read the bodies already stored.
+    pub fn with_encoding(mut self, encoding: BlobEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
 
-            let timestamp = message
-                .received_at
-                .as_ref()
-                .expect("Couldn't get timestamp");
-            let received_at = DateTime::<Utc>::from_utc(
-                NaiveDateTime::from_timestamp(timestamp.seconds, timestamp.nanos as u32),
-                Utc,
-            );
+    /// Run transactions on `pool`, which may be shared with other stores.
+    pub fn with_pool(mut self, pool: CpuPool) -> Self {
+        self.pool = pool;
+        self
+    }
+}
 
-            let received = received_at.date();
-            let received = to_integer_date(received);
+impl TransactionalStore for DB {
+    fn transact<T, F>(&self, mut f: F) -> StorageFuture<T>
+    where
+        T: Send + 'static,
+        F: FnMut(&dyn StorageTransaction) -> std::result::Result<T, StorageError> + Send + 'static,
+    {
+        let db = self.db.clone();
+        let prefix = self.prefix.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            db.transact(move |trx| {
+                f(&FdbTransaction {
+                    trx: &trx,
+                    prefix: &prefix,
+                })
+            })
+            .wait()
+        }))
+    }
 
-            // Set blob for `from` client ID
-            let subkey1 = (message.to.clone(), message.hash.clone());
-            set_blob(&trx, &m_subspace.subspace(subkey1.clone()), &buf, received);
-            // Set blob for `to` client ID
-            let subkey2 = (message.from.clone(), message.hash.clone());
-            set_blob(&trx, &m_subspace.subspace(subkey2.clone()), &buf, received);
+    /// The watch is set by a transaction which first checks `value`, so a
+    /// change committed in between isn't missed. Fdb limits the watches a
+    /// client can hold, and a watch which fails, such as when there are too
+    /// many, returns the error rather than waiting again straight away.
+    fn watch(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> StorageFuture<()> {
+        use futures::future::Either;
+
+        let db = self.db.clone();
+        let mut prefixed = self.prefix.clone();
+        prefixed.extend_from_slice(&key);
+        let watch = self.pool.spawn_fn(move || {
+            db.transact(move |trx| -> std::result::Result<_, StorageError> {
+                let current = trx.get(&prefixed, false).wait()?;
+                if current.value()?.map(|current| current.to_vec()) != value {
+                    return Ok(None);
+                }
+                Ok(Some(trx.watch(&prefixed)))
+            })
+            .wait()
+        });
 
-            // Set expiry keys
-            let exp_subspace = Subspace::from(("R", received));
-            trx.set(&exp_subspace.pack(subkey1), &().to_vec());
-            trx.set(&exp_subspace.pack(subkey2), &().to_vec());
+        Box::new(watch.and_then(|watch| match watch {
+            Some(watch) => Either::A(watch.map_err(StorageError::from)),
+            None => Either::B(futures::future::ok(())),
+        }))
+    }
 
-            // Return message
-            futures::future::ok(message.clone())
-        })
+    fn retention(&self) -> Retention {
+        self.retention.clone()
     }
 
-    pub fn get_messages_for<F>(
-        &self,
-        client_id: &str,
-        filterFn: F,
-    ) -> Box<dyn Future<Item = Vec<proto::Message>, Error = StorageError>>
-    where
-        F: Fn(&[u8]) -> bool + 'static,
-    {
-        use chrono::prelude::*;
-        use foundationdb::transaction::RangeOptionBuilder;
-        use futures::{stream, Stream};
-        use prost::Message;
-        use std::sync::atomic::{AtomicI64, Ordering};
-        use std::sync::Arc;
-
-        let expiry_time = Utc::now() - chrono::Duration::days(self.expiry_days);
-
-        let range = RangeOptionBuilder::from(("M", client_id)).build();
-
-        let message_bytes = Arc::new(AtomicI64::new(0));
-
-        self.db.transact(move |trx| {
-            let range = range.clone();
-
-            // bytebuffer for message bytes
-            let mut buf = Vec::new();
-
-            let messages: Vec<proto::Message> = trx
-                .get_ranges(range)
-                .map_err(StorageError::from)
-                .map(|item| {
-                    let kvs = item.key_values();
-                    let mut messages: Vec<proto::Message> = vec![];
-                    for kv in kvs.as_ref() {
-                        let result: Result<BlobKey> = Decode::try_from(kv.key());
-                        match result {
-                            Ok((_prefix, _client_id, hash, _n)) => {
-                                if filterFn(&hash) {
-                                    match proto::BlobValue::decode(kv.value()) {
-                                        Ok(mut blob_value) => {
-                                            // check if we've hit size limit, if so, break out of loop
-                                            let bytes = message_bytes.load(Ordering::SeqCst);
-                                            if bytes > 0
-                                                && blob_value.blob_length + bytes
-                                                    > RESPONSE_SIZE_LIMIT
-                                            {
-                                                return stream::iter_ok::<
-                                                    Vec<proto::Message>,
-                                                    StorageError,
-                                                >(
-                                                    messages
-                                                );
-                                            }
-                                            buf.append(&mut blob_value.payload);
-                                            if buf.len() == blob_value.blob_length as usize {
-                                                match proto::Message::decode(&buf) {
-                                                    Ok(message) => {
-                                                        let timestamp = message
-                                                            .received_at
-                                                            .as_ref()
-                                                            .expect("Couldn't get timestamp");
-                                                        let received_at = DateTime::<Utc>::from_utc(
-                                                            NaiveDateTime::from_timestamp(
-                                                                timestamp.seconds,
-                                                                timestamp.nanos as u32,
-                                                            ),
-                                                            Utc,
-                                                        );
-                                                        if received_at > expiry_time {
-                                                            message_bytes.fetch_add(
-                                                                blob_value.blob_length,
-                                                                Ordering::SeqCst,
-                                                            );
-                                                            messages.push(message);
-                                                        }
-                                                    }
-                                                    Err(err) => {
-                                                        error!(
-                                                            "failed to decode message: {:?}",
-                                                            err
-                                                        );
-                                                        metrics::MESSAGE_DECODE_FAILURE.inc();
-                                                    }
-                                                }
-                                                buf.clear();
-                                            }
-                                        }
-                                        Err(err) => {
-                                            error!("failed to decode blob value: {:?}", err)
-                                        }
-                                    }
-                                }
-                            }
-                            Err(err) => error!("failed to decode blob key: {:?}", err),
-                        }
-                    }
-                    stream::iter_ok::<Vec<proto::Message>, StorageError>(messages)
-                })
-                .flatten()
-                .collect()
-                .wait()?;
+    fn encoding(&self) -> BlobEncoding {
+        self.encoding.clone()
+    }
+}
 
-            Ok(messages)
-        })
+/// The key prefix for the keyspace `path`, which is packed as a tuple. No
+/// packed path is a prefix of another unless it's one of its ancestors.
+fn keyspace_prefix(path: &[String]) -> Vec<u8> {
+    path.iter()
+        .flat_map(|name| name.as_str().to_vec())
+        .collect()
+}
+
+/// Adapts an Fdb transaction for the mailbox operations.
+struct FdbTransaction<'a> {
+    trx: &'a Transaction,
+    prefix: &'a [u8],
+}
+
+impl<'a> FdbTransaction<'a> {
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        let mut prefixed = Vec::with_capacity(self.prefix.len() + key.len());
+        prefixed.extend_from_slice(self.prefix);
+        prefixed.extend_from_slice(key);
+        prefixed
+    }
+}
+
+impl<'a> StorageTransaction for FdbTransaction<'a> {
+    fn get(&self, key: &[u8]) -> std::result::Result<Option<Vec<u8>>, StorageError> {
+        let result = self.trx.get(&self.key(key), false).wait()?;
+        Ok(result.value()?.map(|value| value.to_vec()))
     }
 
-    pub fn clear_expired(&self) -> Box<dyn Future<Item = (), Error = StorageError>> {
-        use chrono::prelude::*;
+    fn scan(
+        &self,
+        begin: &[u8],
+        end: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> std::result::Result<(), StorageError> {
         use foundationdb::keyselector::KeySelector;
         use foundationdb::transaction::RangeOptionBuilder;
         use futures::Stream;
-        use std::sync::atomic::{AtomicUsize, Ordering};
-        use std::sync::Arc;
-
-        let expiry_date = (Utc::now() - chrono::Duration::days(self.expiry_days)).date();
-        let expiry = to_integer_date(expiry_date);
-
-        let start = KeySelector::first_greater_or_equal(&("R", 0).to_vec());
-        let end = KeySelector::last_less_or_equal(&("R", expiry).to_vec());
-
-        let range = RangeOptionBuilder::new(start.clone(), end.clone()).build();
-
-        let count = Arc::new(AtomicUsize::new(0));
-        let count_inner = count.clone();
-
-        info!("Looking for expired keys");
-
-        let result = self.db.transact(move |trx| {
-            let range = range.clone();
-            trx.get_ranges(range)
-                .map_err(StorageError::from)
-                .map(|item| {
-                    let kvs = item.key_values();
-                    for kv in kvs.as_ref() {
-                        let result: Result<ExpKey> = Decode::try_from(kv.key());
-                        match result {
-                            Ok(t) => {
-                                let (_prefix, _expiry, client_id, hash) = t;
-                                // Clear this message
-                                trx.clear_subspace_range(Subspace::from(("M", client_id, hash)));
-                                count_inner.fetch_add(1, Ordering::SeqCst);
-                            }
-                            Err(err) => {
-                                error!("error decoding key: {:?}", err);
-                            }
-                        }
-                    }
-                })
-                .collect()
-                .wait()?;
 
-            // Clear range of received timestamp keys
-            trx.clear_range(start.key(), end.key());
+        let range = RangeOptionBuilder::new(
+            KeySelector::first_greater_or_equal(&self.key(begin)),
+            KeySelector::first_greater_or_equal(&self.key(end)),
+        )
+        .build();
+
+        for item in self.trx.get_ranges(range).wait() {
+            let item = item?;
+            let kvs = item.key_values();
+            for kv in kvs.as_ref() {
+                if !f(&kv.key()[self.prefix.len()..], kv.value()) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
 
-            Ok(())
-        });
+    fn set(&self, key: &[u8], value: &[u8]) {
+        self.trx.set(&self.key(key), value);
+    }
 
-        info!("Cleared {} expired keys", count.load(Ordering::SeqCst));
+    fn clear(&self, key: &[u8]) {
+        self.trx.clear(&self.key(key));
+    }
+
+    fn clear_range(&self, begin: &[u8], end: &[u8]) {
+        self.trx.clear_range(&self.key(begin), &self.key(end));
+    }
+
+    fn read_version(&self) -> std::result::Result<i64, StorageError> {
+        Ok(self.trx.get_read_version().wait()?)
+    }
 
-        result
+    fn set_read_version(&self, version: i64) {
+        self.trx.set_read_version(version);
     }
 }
 
+/// Fixtures shared by the tests of every module, and the behaviour every
+/// `MessageStore` must have whichever backend it runs on. The checks use
+/// client IDs of their own, so the store may be shared with other tests.
 #[cfg(test)]
-mod tests {
-    extern crate rand;
-    use crate::messages::Timestamped;
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
+pub mod testing {
     use super::*;
+    use crate::messages::Timestamped;
+    use crate::storage::embedded::{EmbeddedDB, EmbeddedKeyValues};
+    use crate::storage::local::LocalDB;
+
+    /// A message received at the start of 1970, so already expired.
+    pub fn make_message(hash: &str, from: &str, to: &str, body: Vec<u8>) -> proto::Message {
+        proto::Message {
+            hash: hash.into(),
+            from: from.into(),
+            to: to.into(),
+            received_at: Some(proto::Timestamp {
+                seconds: 1,
+                nanos: 2,
+            }),
+            body,
+            nonce: "".into(),
+            sender_public_key: "1".into(),
+            recipient_public_key: "2".into(),
+            sent_at: Some(proto::Timestamp {
+                seconds: 1,
+                nanos: 2,
+            }),
+            signature: "signature".into(),
+            value_cents: 0,
+            ttl_seconds: 0,
+        }
+    }
 
-    lazy_static! {
-        static ref TEST_DB: DB = { DB::new(1) };
+    /// An embedded store in a temporary directory, removed when it's dropped.
+    pub fn make_embedded_db(expiry_days: i64) -> EmbeddedDB {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        LocalDB::with_key_values(EmbeddedKeyValues::new(db), expiry_days)
     }
 
-    #[test]
-    fn small_blob_test() {
-        use self::rand::{thread_rng, Rng, RngCore};
-        let rand_prefix = thread_rng().next_u64();
-
-        for _ in 0..10 {
-            let mut arr = [0u8; 5];
-            thread_rng().fill(&mut arr[..]);
-
-            let message = proto::Message {
-                hash: "hash".into(),
-                from: format!("from id {}", rand_prefix),
-                to: format!("to id {}", rand_prefix),
-                received_at: Some(proto::Timestamp {
-                    seconds: 1,
-                    nanos: 2,
-                }),
-                body: arr.to_vec(),
-                nonce: "".into(),
-                sender_public_key: "1".into(),
-                recipient_public_key: "2".into(),
-                sent_at: Some(proto::Timestamp {
-                    seconds: 1,
-                    nanos: 2,
-                }),
-                signature: "signature".into(),
-                value_cents: 0,
-            };
+    fn unique_client_id(name: &str) -> String {
+        format!("{} {}", name, rand::random::<u64>())
+    }
 
-            let future = TEST_DB.insert_message(message.clone());
-            let stored_message = future.wait().unwrap();
-            assert_eq!(message, stored_message);
+    /// Bodies are stored once per hash, whoever they're between, so every
+    /// check's hashes are unique too.
+    fn unique_hash(label: &str) -> String {
+        format!("{} {}", label, rand::random::<u64>())
+    }
+
+    pub fn check_small_blobs(db: &dyn MessageStore) {
+        check_blobs(db, 10, 5);
+    }
+
+    /// Bodies too big for a single Fdb value are split into chunks.
+    pub fn check_big_blobs(db: &dyn MessageStore) {
+        check_blobs(db, 3, 40000);
+    }
+
+    fn check_blobs(db: &dyn MessageStore, count: usize, size: usize) {
+        use rand::{thread_rng, Rng};
+        let (from, to) = (unique_client_id("from id"), unique_client_id("to id"));
 
-            let future = TEST_DB.get_messages_for(&format!("from id {}", rand_prefix), |_| true);
-            let result = future.wait();
+        for n in 0..count {
+            let mut body = vec![0u8; size];
+            thread_rng().fill(&mut body[..]);
 
-            assert_eq!(result.is_ok(), true);
-            assert_eq!(
-                result
+            let message =
+                make_message(&unique_hash(&format!("hash{}", n)), &from, &to, body).timestamped();
+            let stored_message = db.insert_message(message.clone()).wait().unwrap().message;
+            assert_eq!(message, stored_message);
+
+            for client_id in &[&from, &to] {
+                let result = db
+                    .get_messages_for(client_id, None, Box::new(|_| true))
+                    .wait()
                     .unwrap()
-                    .iter()
-                    .any(|m| m.hash == stored_message.hash),
-                true
-            );
+                    .messages;
+                assert_eq!(result.len(), n + 1);
+                assert_eq!(result.iter().any(|m| m == &stored_message), true);
+            }
         }
     }
 
-    #[test]
-    fn big_blob_test() {
-        use self::rand::{thread_rng, Rng, RngCore};
-        let rand_prefix = thread_rng().next_u64();
-
-        for n in 0..3 {
-            let mut arr = [0u8; 40000];
-            thread_rng().fill(&mut arr[..]);
-
-            let message = proto::Message {
-                hash: format!("hash{}", n).into(),
-                from: format!("from id {}", rand_prefix),
-                to: format!("to id {}", rand_prefix),
-                received_at: Some(proto::Timestamp {
-                    seconds: 1,
-                    nanos: 2,
-                }),
-                body: arr.to_vec(),
-                nonce: "".into(),
-                sender_public_key: "1".into(),
-                recipient_public_key: "2".into(),
-                sent_at: Some(proto::Timestamp {
-                    seconds: 1,
-                    nanos: 2,
-                }),
-                signature: "signature".into(),
-                value_cents: 0,
-            };
+    pub fn check_filter(db: &dyn MessageStore) {
+        let (from, to) = (unique_client_id("from id"), unique_client_id("to id"));
+        let (keep, skip) = (unique_hash("keep"), unique_hash("skip"));
 
-            let future = TEST_DB.insert_message(message.clone());
-            let stored_message = future.wait().unwrap();
-            assert_eq!(message, stored_message);
+        for hash in &[&keep, &skip] {
+            let message = make_message(hash, &from, &to, vec![1, 2, 3]).timestamped();
+            db.insert_message(message).wait().unwrap();
+        }
+
+        let skipped = skip.clone();
+        let result = db
+            .get_messages_for(&to, None, Box::new(move |hash| hash != skipped.as_bytes()))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].hash, keep.as_bytes());
+    }
 
-            let future = TEST_DB.get_messages_for(&format!("from id {}", rand_prefix), |_| true);
-            let result = future.wait();
+    /// Returns the senders of the expired messages and of those kept.
+    pub fn check_expired_keys(db: &dyn MessageStore) -> (String, String) {
+        let expired = unique_client_id("expired");
+        let not_expired = unique_client_id("not expired");
+        let nowhere = unique_client_id("nowhere");
+        let n = 10;
 
-            assert_eq!(result.is_ok(), true);
-            assert_eq!(
-                result
-                    .unwrap()
-                    .iter()
-                    .any(|m| m.hash == stored_message.hash),
-                true
+        for i in 0..n {
+            let expired_message = make_message(
+                &unique_hash(&format!("hash A {}", i)),
+                &expired,
+                &nowhere,
+                vec![1, 2, 3],
             );
+            let not_expired_message = make_message(
+                &unique_hash(&format!("hash B {}", i)),
+                &not_expired,
+                &nowhere,
+                vec![1, 2, 3],
+            )
+            .timestamped();
+            db.insert_message(expired_message).wait().unwrap();
+            db.insert_message(not_expired_message).wait().unwrap();
+        }
+
+        let mut cursor = None;
+        loop {
+            let (_, next) = db.clear_expired(cursor, 100).wait().unwrap();
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        let result = db
+            .get_messages_for(&expired, None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result.len(), 0);
+        let result = db
+            .get_messages_for(&not_expired, None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result.len(), n);
+
+        (expired, not_expired)
+    }
+
+    /// Responses stop at `RESPONSE_SIZE_LIMIT`, and the cursor picks up
+    /// where they stopped.
+    pub fn check_pagination(db: &dyn MessageStore) {
+        let (from, to) = (unique_client_id("from id"), unique_client_id("to id"));
+
+        let expected: Vec<String> = (0..25)
+            .map(|n| unique_hash(&format!("hash{:02}", n)))
+            .collect();
+        for hash in &expected {
+            let message = make_message(hash, &from, &to, vec![0u8; 1_048_576]).timestamped();
+            db.insert_message(message).wait().unwrap();
+        }
+
+        let mut cursor = None;
+        let mut hashes = vec![];
+        let mut pages = 0;
+        loop {
+            let page = db
+                .get_messages_for(&to, cursor, Box::new(|_| true))
+                .wait()
+                .unwrap();
+            hashes.extend(page.messages.into_iter().map(|m| m.hash));
+            pages += 1;
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
         }
+
+        assert_eq!(pages, 3);
+        let expected: Vec<Vec<u8>> = expected.into_iter().map(String::into_bytes).collect();
+        assert_eq!(hashes, expected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::storage::memory::MemoryDB;
+
+    lazy_static! {
+        static ref TEST_DB: DB = { DB::new(&FdbOptions::default(), 1).unwrap() };
+    }
+
+    /// Run `check` against the Fdb test database, and fresh stores of the
+    /// other backends.
+    fn for_each_backend<F: Fn(&dyn MessageStore)>(check: F) {
+        check(&*TEST_DB);
+        check(&MemoryDB::new(1));
+        check(&testing::make_embedded_db(1));
+    }
+
+    #[test]
+    fn small_blob_test() {
+        for_each_backend(testing::check_small_blobs);
+    }
+
+    #[test]
+    fn big_blob_test() {
+        for_each_backend(testing::check_big_blobs);
+    }
+
+    #[test]
+    fn filter_test() {
+        for_each_backend(testing::check_filter);
     }
 
     #[test]
     fn expired_keys_test() {
-        use self::rand::{thread_rng, Rng, RngCore};
-        let rand_prefix = thread_rng().next_u64();
-        let n = 10;
+        for_each_backend(|db| {
+            testing::check_expired_keys(db);
+        });
+    }
 
-        for i in 0..n {
-            let mut arr = [0u8; 5];
-            thread_rng().fill(&mut arr[..]);
-
-            let expired_message = proto::Message {
-                hash: format!("hash A {} {}", i, rand_prefix).into(),
-                from: format!("expired {}", rand_prefix),
-                to: format!("nowhere {}", rand_prefix),
-                received_at: Some(proto::Timestamp {
-                    seconds: 1 + n as i64,
-                    nanos: 2,
-                }),
-                body: arr.to_vec(),
-                nonce: "".into(),
-                sender_public_key: "1".into(),
-                recipient_public_key: "2".into(),
-                sent_at: Some(proto::Timestamp {
-                    seconds: 1 + n as i64,
-                    nanos: 2,
-                }),
-                signature: "signature".into(),
-                value_cents: 0,
-            };
+    #[test]
+    fn pagination_test() {
+        for_each_backend(testing::check_pagination);
+    }
 
-            let not_expired_message = proto::Message {
-                hash: format!("hash B {} {}", i, rand_prefix).into(),
-                from: format!("not expired {}", rand_prefix),
-                to: format!("nowhere {}", rand_prefix),
-                received_at: None,
-                body: arr.to_vec(),
-                nonce: "".into(),
-                sender_public_key: "1".into(),
-                recipient_public_key: "2".into(),
-                sent_at: Some(proto::Timestamp {
-                    seconds: 1 + n as i64,
-                    nanos: 2,
-                }),
-                signature: "signature".into(),
-                value_cents: 0,
-            }
-            .timestamped();
+    #[test]
+    fn insert_messages_test() {
+        use crate::messages::Timestamped;
+
+        let db = MemoryDB::new(1);
+        let messages: Vec<_> = (1..4)
+            .map(|n| {
+                let hash = format!("hash {}", n);
+                testing::make_message(&hash, "from id", "to id", vec![0u8; 3_000_000]).timestamped()
+            })
+            .collect();
+        assert_eq!(
+            size_batches(messages.clone(), INSERT_BATCH_SIZE_LIMIT).len(),
+            3
+        );
+
+        // The second batch fails on a body it can't decode, and the others
+        // are committed anyway
+        db.transact(|trx| {
+            let (begin, _end) = body_range(b"hash 2");
+            trx.set(&begin, &[0xff]);
+            Ok(())
+        })
+        .wait()
+        .unwrap();
+        let results = db.insert_messages(messages.clone()).wait().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().message, messages[0]);
+        match results[1] {
+            Err(StorageError::DecodingFailure { .. }) => (),
+            ref other => panic!("expected a decoding failure, got {:?}", other),
+        }
+        assert_eq!(results[2].as_ref().unwrap().message, messages[2]);
 
-            let future = TEST_DB.insert_message(expired_message.clone());
-            let stored_message = future.wait().unwrap();
-            assert_eq!(expired_message, stored_message);
-            let future = TEST_DB.insert_message(not_expired_message.clone());
-            let stored_message = future.wait().unwrap();
-            assert_eq!(not_expired_message, stored_message);
+        let stored = db
+            .get_messages_for("to id", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(stored, vec![messages[0].clone(), messages[2].clone()]);
+    }
+
+    #[test]
+    fn backup_keys_test() {
+        // A keyspace of its own, so the backup only reads this test's keys
+        let keyspace = vec![format!("backup_keys_test_{}", rand::random::<u64>())];
+        let db = DB::new(&FdbOptions::default(), 1)
+            .unwrap()
+            .with_keyspace(&keyspace);
+        let insert = |hash: &str| {
+            db.insert_message(testing::make_message(hash, "alice", "bob", "yo".into()))
+                .wait()
+                .unwrap()
+        };
+        for n in 0..10 {
+            insert(&format!("hash {}", n));
         }
 
-        TEST_DB.clear_expired().wait().unwrap();
+        // Every batch after the first reads at its version, in a transaction
+        // of its own, so a message inserted in between isn't backed up
+        let first = db.backup_keys(None, None, 5).wait().unwrap();
+        insert("hash 10");
+        let mut kvs = first.kvs;
+        let mut cursor = first.cursor;
+        let mut batches = 1;
+        while cursor.is_some() {
+            let batch = db
+                .backup_keys(Some(first.version), cursor, 5)
+                .wait()
+                .unwrap();
+            assert_eq!(batch.version, first.version);
+            kvs.extend(batch.kvs);
+            cursor = batch.cursor;
+            batches += 1;
+        }
+        assert_eq!(batches > 2, true);
+        let latest = db.backup_keys(None, None, 1_000_000).wait().unwrap();
+        assert_eq!(latest.cursor, None);
+        assert_eq!(kvs.len() < latest.kvs.len(), true);
+
+        // A version Fdb no longer serves fails straight away
+        let stale = first.version - 60_000_000;
+        match db.backup_keys(Some(stale), None, 5).wait() {
+            Err(StorageError::VersionTooOld { version, .. }) => assert_eq!(version, stale),
+            other => panic!("expected VersionTooOld, got {:?}", other),
+        }
 
-        let future = TEST_DB.get_messages_for(&format!("expired {}", rand_prefix), |_| true);
-        let result = future.wait();
+        let (begin, end) = KEYSPACE_RANGE;
+        db.transact(move |trx| {
+            trx.clear_range(begin, end);
+            Ok(())
+        })
+        .wait()
+        .unwrap();
+    }
 
-        assert_eq!(result.is_ok(), true);
-        assert_eq!(result.unwrap().len(), 0);
+    #[test]
+    fn size_batches_test() {
+        let message = |hash: &str, size: usize| proto::Message {
+            hash: hash.into(),
+            body: vec![0u8; size],
+            ..Default::default()
+        };
+        let hashes = |batches: Vec<Vec<proto::Message>>| -> Vec<Vec<Vec<u8>>> {
+            batches
+                .into_iter()
+                .map(|batch| batch.into_iter().map(|m| m.hash).collect())
+                .collect()
+        };
 
-        let future = TEST_DB.get_messages_for(&format!("not expired {}", rand_prefix), |_| true);
-        let result = future.wait();
+        assert_eq!(size_batches(vec![], 100).len(), 0);
+        let messages = vec![
+            message("a", 40),
+            message("b", 40),
+            message("c", 200),
+            message("d", 10),
+        ];
+        assert_eq!(
+            hashes(size_batches(messages, 100)),
+            vec![
+                vec![b"a".to_vec(), b"b".to_vec()],
+                vec![b"c".to_vec()],
+                vec![b"d".to_vec()],
+            ]
+        );
+    }
 
-        assert_eq!(result.is_ok(), true);
-        let result = result.unwrap();
-        assert_eq!(result.len(), n);
+    #[test]
+    fn keyspace_prefix_test() {
+        let path =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|n| n.to_string()).collect() };
+
+        assert_eq!(keyspace_prefix(&[]), Vec::<u8>::new());
+        assert_eq!(
+            keyspace_prefix(&path(&["switchroom", "staging"])),
+            ("switchroom", "staging").to_vec()
+        );
+
+        // Sibling paths don't nest, even when one name extends the other
+        let staging = keyspace_prefix(&path(&["staging"]));
+        let staging2 = keyspace_prefix(&path(&["staging2"]));
+        assert_eq!(staging2.starts_with(&staging), false);
+        assert_eq!(
+            keyspace_prefix(&path(&["staging", "a"])).starts_with(&staging),
+            true
+        );
     }
 }
@@ -0,0 +1,172 @@
+// An on-disk key-value store for `LocalDB`, backed by an embedded sled
+// database. Intended for single-node staging and dev deployments which
+// don't want to run an Fdb cluster.
+use super::local::{KeyValues, LocalDB, Write, WriteBatch};
+use super::StorageError;
+
+use std::collections::BTreeMap;
+
+pub struct EmbeddedKeyValues {
+    db: sled::Db,
+}
+
+pub type EmbeddedDB = LocalDB<EmbeddedKeyValues>;
+
+impl From<sled::Error> for StorageError {
+    fn from(err: sled::Error) -> StorageError {
+        StorageError::EmbeddedError {
+            err: err.to_string(),
+        }
+    }
+}
+
+impl EmbeddedKeyValues {
+    pub fn new(db: sled::Db) -> Self {
+        EmbeddedKeyValues { db }
+    }
+}
+
+impl LocalDB<EmbeddedKeyValues> {
+    pub fn open(path: &str, expiry_days: i64) -> Result<Self, StorageError> {
+        let db = sled::open(path)?;
+        Ok(LocalDB::with_key_values(
+            EmbeddedKeyValues::new(db),
+            expiry_days,
+        ))
+    }
+}
+
+impl KeyValues for EmbeddedKeyValues {
+    fn scan(
+        &self,
+        begin: &[u8],
+        end: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), StorageError> {
+        for item in self.db.range(begin..end) {
+            let (key, value) = item?;
+            if !f(&key, &value) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), StorageError> {
+        // sled has no range clears, so they're expanded into the keys they
+        // cover, including those set earlier in the batch
+        let mut writes: BTreeMap<Vec<u8>, Option<Vec<u8>>> = BTreeMap::new();
+        for write in batch.into_writes() {
+            match write {
+                Write::Set(key, value) => {
+                    writes.insert(key, Some(value));
+                }
+                Write::Clear(key) => {
+                    writes.insert(key, None);
+                }
+                Write::ClearRange(begin, end) => {
+                    if begin >= end {
+                        continue;
+                    }
+                    for item in self.db.range(begin.clone()..end.clone()) {
+                        let (key, _) = item?;
+                        writes.insert(key.to_vec(), None);
+                    }
+                    for (_, value) in writes.range_mut(begin..end) {
+                        *value = None;
+                    }
+                }
+            }
+        }
+
+        let mut sled_batch = sled::Batch::default();
+        for (key, value) in writes {
+            match value {
+                Some(value) => sled_batch.insert(key, value),
+                None => sled_batch.remove(key),
+            }
+        }
+        self.db.apply_batch(sled_batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::messages::Timestamped;
+    use crate::storage::memory::MemoryKeyValues;
+    use crate::storage::testing;
+    use crate::storage::MessageStore;
+    use futures::Future;
+
+    fn scan_all(kvs: &dyn KeyValues) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut result = vec![];
+        kvs.scan(b"", b"\xff", &mut |key, value| {
+            result.push((key.to_vec(), value.to_vec()));
+            true
+        })
+        .unwrap();
+        result
+    }
+
+    #[test]
+    fn clear_range_test() {
+        let embedded = EmbeddedKeyValues::new(sled::Config::new().temporary(true).open().unwrap());
+        let memory = MemoryKeyValues::default();
+
+        let stores: [&dyn KeyValues; 2] = [&embedded, &memory];
+        for kvs in &stores {
+            let mut batch = WriteBatch::default();
+            for key in &[b"a", b"b", b"c", b"d"] {
+                batch.set(key.to_vec(), b"1".to_vec());
+            }
+            kvs.write(batch).unwrap();
+
+            // The range covers stored keys and keys set earlier in the
+            // batch, but not its end or keys set after it
+            let mut batch = WriteBatch::default();
+            batch.set(b"bb".to_vec(), b"2".to_vec());
+            batch.clear_range(b"b".to_vec(), b"d".to_vec());
+            batch.set(b"c".to_vec(), b"3".to_vec());
+            kvs.write(batch).unwrap();
+        }
+
+        assert_eq!(
+            scan_all(&embedded),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"c".to_vec(), b"3".to_vec()),
+                (b"d".to_vec(), b"1".to_vec()),
+            ]
+        );
+        assert_eq!(scan_all(&embedded), scan_all(&memory));
+    }
+
+    #[test]
+    fn reopen_test() {
+        let path = std::env::temp_dir().join(format!("switchroom-test-{}", rand::random::<u64>()));
+        let path = path.to_str().unwrap();
+
+        let message =
+            testing::make_message("hash", "from id", "to id", vec![7u8; 40000]).timestamped();
+        {
+            let db = EmbeddedDB::open(path, 1).unwrap();
+            db.insert_message(message.clone()).wait().unwrap();
+        }
+
+        // Everything was flushed when the write was committed
+        let db = EmbeddedDB::open(path, 1).unwrap();
+        let result = db
+            .get_messages_for("to id", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result, vec![message]);
+
+        drop(db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+// Data keys for encrypting message blobs at rest. The keyring is a TOML file
+// naming the key used for new blobs, plus every key still needed to read old
+// ones:
+//
+// active_key = "2019-11"
+//
+// [keys]
+// "2019-10" = "<base64 encoded 32 byte key>"
+// "2019-11" = "<base64 encoded 32 byte key>"
+//
+// To rotate, add a new key, make it active, run switchroom-reencrypt, then
+// drop the old key.
+use super::StorageError;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use std::collections::HashMap;
+use std::fs;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+#[derive(Debug, Deserialize)]
+struct KeyringFile {
+    active_key: String,
+    keys: HashMap<String, String>,
+}
+
+pub struct Keyring {
+    active_key: String,
+    ciphers: HashMap<String, Aes256Gcm>,
+}
+
+fn keyring_error(err: String) -> StorageError {
+    StorageError::KeyringError { err }
+}
+
+impl Keyring {
+    /// Build a keyring from raw 32 byte keys. `active_key` must be one of
+    /// them.
+    pub fn new(active_key: &str, keys: HashMap<String, Vec<u8>>) -> Result<Self, StorageError> {
+        if !keys.contains_key(active_key) {
+            return Err(keyring_error(format!(
+                "active key {:?} is not in the keyring",
+                active_key
+            )));
+        }
+
+        let mut ciphers = HashMap::new();
+        for (key_id, key) in keys {
+            if key.len() != KEY_SIZE {
+                return Err(keyring_error(format!(
+                    "key {:?} is {} bytes, expected {}",
+                    key_id,
+                    key.len(),
+                    KEY_SIZE
+                )));
+            }
+            ciphers.insert(key_id, Aes256Gcm::new(GenericArray::from_slice(&key)));
+        }
+
+        Ok(Keyring {
+            active_key: active_key.to_string(),
+            ciphers,
+        })
+    }
+
+    /// Load a keyring file.
+    pub fn load(path: &str) -> Result<Self, StorageError> {
+        use data_encoding::BASE64;
+
+        let contents = fs::read_to_string(path)
+            .map_err(|err| keyring_error(format!("unable to read {}: {}", path, err)))?;
+        let file: KeyringFile = toml::from_str(&contents)
+            .map_err(|err| keyring_error(format!("unable to parse {}: {}", path, err)))?;
+
+        let mut keys = HashMap::new();
+        for (key_id, key) in file.keys {
+            let key = BASE64
+                .decode(key.as_bytes())
+                .map_err(|err| keyring_error(format!("key {:?}: {}", key_id, err)))?;
+            keys.insert(key_id, key);
+        }
+        Keyring::new(&file.active_key, keys)
+    }
+
+    /// The ID of the key new blobs are encrypted with.
+    pub fn active_key(&self) -> &str {
+        &self.active_key
+    }
+
+    /// Encrypt `plaintext` with the active key, bound to `aad`. Returns the ID
+    /// of the key used, and the nonce followed by the ciphertext.
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<(String, Vec<u8>), StorageError> {
+        use rand::RngCore;
+
+        let cipher = &self.ciphers[&self.active_key];
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = cipher
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| keyring_error("encryption failed".to_string()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok((self.active_key.clone(), sealed))
+    }
+
+    /// Decrypt the output of `encrypt`, which must have been bound to the same
+    /// `aad`.
+    pub fn decrypt(
+        &self,
+        key_id: &str,
+        sealed: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, StorageError> {
+        let cipher = self
+            .ciphers
+            .get(key_id)
+            .ok_or_else(|| keyring_error(format!("no key {:?} in the keyring", key_id)))?;
+        if sealed.len() < NONCE_SIZE {
+            return Err(keyring_error("ciphertext is truncated".to_string()));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        cipher
+            .decrypt(
+                GenericArray::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| keyring_error(format!("unable to decrypt with key {:?}", key_id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn make_keyring(active_key: &str) -> Keyring {
+        let mut keys = HashMap::new();
+        keys.insert("old".to_string(), vec![1u8; KEY_SIZE]);
+        keys.insert("new".to_string(), vec![2u8; KEY_SIZE]);
+        Keyring::new(active_key, keys).unwrap()
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let keyring = make_keyring("new");
+
+        let (key_id, sealed) = keyring.encrypt(b"envelope", b"hash").unwrap();
+        assert_eq!(key_id, "new");
+        assert_eq!(sealed.windows(8).any(|w| w == b"envelope"), false);
+        assert_eq!(
+            keyring.decrypt(&key_id, &sealed, b"hash").unwrap(),
+            b"envelope".to_vec()
+        );
+
+        // The ciphertext is bound to its key and to the associated data
+        assert_eq!(keyring.decrypt("old", &sealed, b"hash").is_err(), true);
+        assert_eq!(keyring.decrypt(&key_id, &sealed, b"other").is_err(), true);
+        assert_eq!(keyring.decrypt("missing", &sealed, b"hash").is_err(), true);
+    }
+
+    #[test]
+    fn invalid_keyring_test() {
+        let mut keys = HashMap::new();
+        keys.insert("short".to_string(), vec![1u8; 16]);
+        assert_eq!(Keyring::new("short", keys.clone()).is_err(), true);
+        assert_eq!(Keyring::new("missing", keys).is_err(), true);
+    }
+}
@@ -0,0 +1,409 @@
+// A `MessageStore` for single-node deployments, built on top of any ordered
+// key-value store. It runs the same mailbox operations as the Fdb backend
+// against the same tuple key layout, so chunking, expiry and
+// `RESPONSE_SIZE_LIMIT` behave identically. Transactions are serialized by a
+// process-wide lock and run on a thread pool, and their writes are buffered
+// and applied as one batch.
+use super::retention::Retention;
+use super::{BlobEncoding, StorageError, StorageFuture, StorageTransaction, TransactionalStore};
+
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use futures::Future;
+use futures_cpupool::CpuPool;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+pub enum Write {
+    Set(Vec<u8>, Vec<u8>),
+    Clear(Vec<u8>),
+    ClearRange(Vec<u8>, Vec<u8>),
+}
+
+/// A set of writes which must be applied atomically.
+#[derive(Default)]
+pub struct WriteBatch {
+    writes: Vec<Write>,
+}
+
+impl WriteBatch {
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.push(Write::Set(key, value));
+    }
+
+    pub fn clear(&mut self, key: Vec<u8>) {
+        self.writes.push(Write::Clear(key));
+    }
+
+    pub fn clear_range(&mut self, begin: Vec<u8>, end: Vec<u8>) {
+        self.writes.push(Write::ClearRange(begin, end));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Whether applying the batch could change `key`.
+    pub fn touches(&self, key: &[u8]) -> bool {
+        self.writes.iter().any(|write| match write {
+            Write::Set(k, _) | Write::Clear(k) => &k[..] == key,
+            Write::ClearRange(begin, end) => key >= &begin[..] && key < &end[..],
+        })
+    }
+
+    pub fn into_writes(self) -> Vec<Write> {
+        self.writes
+    }
+}
+
+/// An ordered key-value store which can back a `LocalDB`.
+pub trait KeyValues: Send + Sync {
+    /// Visit every key in `[begin, end)` in order, until `f` returns false.
+    fn scan(
+        &self,
+        begin: &[u8],
+        end: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), StorageError>;
+
+    /// Apply every write in `batch`, or none of them.
+    fn write(&self, batch: WriteBatch) -> Result<(), StorageError>;
+}
+
+/// A caller waiting for `key` to change.
+struct Watcher {
+    key: Vec<u8>,
+    sender: oneshot::Sender<()>,
+}
+
+/// Wake the watchers of every key `batch` could change, and forget those no
+/// longer waiting.
+fn wake_watchers(watchers: &Mutex<Vec<Watcher>>, batch: &WriteBatch) {
+    let mut watchers = watchers.lock().unwrap();
+    let (woken, waiting): (Vec<Watcher>, Vec<Watcher>) = watchers
+        .drain(..)
+        .filter(|watcher| !watcher.sender.is_canceled())
+        .partition(|watcher| batch.touches(&watcher.key));
+    *watchers = waiting;
+    for watcher in woken {
+        let _ = watcher.sender.send(());
+    }
+}
+
+pub struct LocalDB<K: KeyValues> {
+    kvs: Arc<K>,
+    write_lock: Arc<Mutex<()>>,
+    /// Registered and woken while holding `write_lock`, so that no write is
+    /// missed.
+    watchers: Arc<Mutex<Vec<Watcher>>>,
+    retention: Retention,
+    encoding: BlobEncoding,
+    /// Runs transactions, so that waiting on the lock doesn't block the
+    /// caller.
+    pool: CpuPool,
+}
+
+// Written out, since a derived impl would need `K: Clone`
+impl<K: KeyValues> Clone for LocalDB<K> {
+    fn clone(&self) -> Self {
+        LocalDB {
+            kvs: self.kvs.clone(),
+            write_lock: self.write_lock.clone(),
+            watchers: self.watchers.clone(),
+            retention: self.retention.clone(),
+            encoding: self.encoding.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<K: KeyValues> LocalDB<K> {
+    pub fn with_key_values(kvs: K, expiry_days: i64) -> Self {
+        LocalDB {
+            kvs: Arc::new(kvs),
+            write_lock: Arc::new(Mutex::new(())),
+            watchers: Arc::new(Mutex::new(vec![])),
+            retention: Retention::new(expiry_days),
+            encoding: BlobEncoding::default(),
+            pool: CpuPool::new(1),
+        }
+    }
+
+    /// Keep messages for the periods set by `retention`, rather than for
+    /// `expiry_days` whoever they're for.
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Encode newly written bodies with `encoding`, which must also be able
+    /// to read the bodies already stored.
+    pub fn with_encoding(mut self, encoding: BlobEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Run transactions on `pool`, which may be shared with other stores.
+    pub fn with_pool(mut self, pool: CpuPool) -> Self {
+        self.pool = pool;
+        self
+    }
+}
+
+impl<K: KeyValues + 'static> TransactionalStore for LocalDB<K> {
+    fn transact<T, F>(&self, mut f: F) -> StorageFuture<T>
+    where
+        T: Send + 'static,
+        F: FnMut(&dyn StorageTransaction) -> Result<T, StorageError> + Send + 'static,
+    {
+        let kvs = self.kvs.clone();
+        let write_lock = self.write_lock.clone();
+        let watchers = self.watchers.clone();
+
+        Box::new(self.pool.spawn_fn(move || {
+            let _lock = write_lock.lock().unwrap();
+
+            let trx = LocalTransaction::new(&*kvs);
+            f(&trx).and_then(|value| {
+                // Read-only transactions have nothing to commit
+                let batch = trx.into_batch();
+                if batch.is_empty() {
+                    return Ok(value);
+                }
+                // Woken watchers check again, so waking them early is harmless
+                wake_watchers(&watchers, &batch);
+                kvs.write(batch).map(|_| value)
+            })
+        }))
+    }
+
+    fn watch(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> StorageFuture<()> {
+        let kvs = self.kvs.clone();
+        let write_lock = self.write_lock.clone();
+        let watchers = self.watchers.clone();
+
+        let watch = self.pool.spawn_fn(move || -> Result<_, StorageError> {
+            let _lock = write_lock.lock().unwrap();
+
+            if LocalTransaction::new(&*kvs).get(&key)? != value {
+                return Ok(None);
+            }
+            let (sender, receiver) = oneshot::channel();
+            watchers.lock().unwrap().push(Watcher { key, sender });
+            Ok(Some(receiver))
+        });
+
+        Box::new(watch.and_then(|receiver| match receiver {
+            Some(receiver) => Either::A(receiver.then(|_| Ok(()))),
+            None => Either::B(future::ok(())),
+        }))
+    }
+
+    fn retention(&self) -> Retention {
+        self.retention.clone()
+    }
+
+    fn encoding(&self) -> BlobEncoding {
+        self.encoding.clone()
+    }
+}
+
+/// Buffers a transaction's writes on top of the committed key values. Reads
+/// see the buffered writes.
+struct LocalTransaction<'a, K: KeyValues + 'a> {
+    kvs: &'a K,
+    /// Ranges cleared by this transaction, applied before `pending`.
+    cleared: RefCell<Vec<(Vec<u8>, Vec<u8>)>>,
+    /// Keys set (`Some`) or cleared (`None`) since the last covering clear.
+    pending: RefCell<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl<'a, K: KeyValues> LocalTransaction<'a, K> {
+    fn new(kvs: &'a K) -> Self {
+        LocalTransaction {
+            kvs,
+            cleared: RefCell::new(vec![]),
+            pending: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn is_cleared(&self, key: &[u8]) -> bool {
+        self.cleared
+            .borrow()
+            .iter()
+            .any(|(begin, end)| key >= &begin[..] && key < &end[..])
+    }
+
+    fn into_batch(self) -> WriteBatch {
+        let mut batch = WriteBatch::default();
+        for (begin, end) in self.cleared.into_inner() {
+            batch.clear_range(begin, end);
+        }
+        for (key, value) in self.pending.into_inner() {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.clear(key),
+            }
+        }
+        batch
+    }
+}
+
+impl<'a, K: KeyValues> StorageTransaction for LocalTransaction<'a, K> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        if let Some(value) = self.pending.borrow().get(key) {
+            return Ok(value.clone());
+        }
+        if self.is_cleared(key) {
+            return Ok(None);
+        }
+
+        let mut end = key.to_vec();
+        end.push(0);
+        let mut result = None;
+        self.kvs.scan(key, &end, &mut |_key, value| {
+            result = Some(value.to_vec());
+            false
+        })?;
+        Ok(result)
+    }
+
+    fn scan(
+        &self,
+        begin: &[u8],
+        end: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), StorageError> {
+        if begin >= end {
+            return Ok(());
+        }
+
+        // Merge the committed values with this transaction's writes before
+        // visiting any, so that `f` is free to write
+        let mut merged = BTreeMap::new();
+        self.kvs.scan(begin, end, &mut |key, value| {
+            if !self.is_cleared(key) {
+                merged.insert(key.to_vec(), value.to_vec());
+            }
+            true
+        })?;
+        for (key, value) in self.pending.borrow().range(begin.to_vec()..end.to_vec()) {
+            match value {
+                Some(value) => merged.insert(key.clone(), value.clone()),
+                None => merged.remove(key),
+            };
+        }
+
+        for (key, value) in &merged {
+            if !f(key, value) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) {
+        self.pending
+            .borrow_mut()
+            .insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    fn clear(&self, key: &[u8]) {
+        self.pending.borrow_mut().insert(key.to_vec(), None);
+    }
+
+    fn clear_range(&self, begin: &[u8], end: &[u8]) {
+        if begin >= end {
+            return;
+        }
+        let mut pending = self.pending.borrow_mut();
+        let keys: Vec<Vec<u8>> = pending
+            .range(begin.to_vec()..end.to_vec())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            pending.remove(&key);
+        }
+        self.cleared
+            .borrow_mut()
+            .push((begin.to_vec(), end.to_vec()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::memory::MemoryKeyValues;
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn scan_keys(trx: &dyn StorageTransaction, begin: &[u8], end: &[u8]) -> Vec<Vec<u8>> {
+        let mut keys = vec![];
+        trx.scan(begin, end, &mut |key, _value| {
+            keys.push(key.to_vec());
+            true
+        })
+        .unwrap();
+        keys
+    }
+
+    #[test]
+    fn reads_see_own_writes_test() {
+        let kvs = MemoryKeyValues::default();
+        let mut batch = WriteBatch::default();
+        batch.set(b"a".to_vec(), b"1".to_vec());
+        batch.set(b"b".to_vec(), b"2".to_vec());
+        batch.set(b"c".to_vec(), b"3".to_vec());
+        kvs.write(batch).unwrap();
+
+        let trx = LocalTransaction::new(&kvs);
+        trx.clear_range(b"a", b"c");
+        trx.set(b"b", b"4");
+        trx.set(b"d", b"5");
+        assert_eq!(trx.get(b"a").unwrap(), None);
+        assert_eq!(trx.get(b"b").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(
+            scan_keys(&trx, b"a", b"z"),
+            vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]
+        );
+
+        // Nothing is visible outside the transaction until it's committed
+        let committed = LocalTransaction::new(&kvs);
+        assert_eq!(committed.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(committed.get(b"d").unwrap(), None);
+
+        kvs.write(trx.into_batch()).unwrap();
+        let committed = LocalTransaction::new(&kvs);
+        assert_eq!(
+            scan_keys(&committed, b"a", b"z"),
+            vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]
+        );
+        assert_eq!(committed.get(b"b").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn watch_test() {
+        let db = LocalDB::with_key_values(MemoryKeyValues::default(), 1);
+        let set = |key: &'static [u8], value: &'static [u8]| {
+            db.transact(move |trx| {
+                trx.set(key, value);
+                Ok(())
+            })
+            .wait()
+            .unwrap()
+        };
+
+        // A key which has already changed resolves straight away
+        db.watch(b"a".to_vec(), Some(b"1".to_vec())).wait().unwrap();
+        assert_eq!(db.watchers.lock().unwrap().len(), 0);
+
+        // Transactions run in order on the pool, so the watch is registered
+        // before either write
+        let watch = db.watch(b"a".to_vec(), None);
+        set(b"b", b"1");
+        assert_eq!(db.watchers.lock().unwrap().len(), 1);
+        set(b"a", b"1");
+        watch.wait().unwrap();
+        assert_eq!(db.watchers.lock().unwrap().len(), 0);
+    }
+}
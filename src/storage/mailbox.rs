@@ -0,0 +1,2182 @@
+// The mailbox operations shared by every backend, written against
+// `StorageTransaction` so that the key layout lives in one place:
+//
+// ("B", hash, offset)          chunks of the single stored copy of a body
+// ("M", client, hash)          mailbox entry, valued with the counterpart's ID
+// ("M", client, hash, offset)  legacy mailbox entry holding its own blob
+// ("T", client, secs, nanos, hash)  received-time index
+// ("E", yyyymmdd, client, hash)     expiry key filed under the expiry date,
+//                                   valued with the received time
+// ("R", yyyymmdd, client, hash)     legacy expiry key filed under the
+//                                   received date, for the default retention
+// ("H", client)                     legal hold, valued with a `LegalHold`
+// ("N", client)                     notification token, rewritten whenever
+//                                   a message is inserted for the client
+// ("V")                             schema version, absent before version 1
+use super::retention::Retention;
+use super::{
+    body_range, counterpart, decode_mailbox_key, decode_time_key, entry_keys, entry_range,
+    expired_time_key, expiry_value, mailbox_key, mailbox_range, message_keys, read_blob,
+    received_at, received_range, resume_from, scan_values, set_blob, time_key, to_integer_date,
+    BlobAssembler, BlobEncoding, BodyKey, ClearedExpired, ExpKey, InsertResult, InsertedMessage,
+    IntegrityReport, MailboxKey, MailboxUpdates, MessageKeys, MessagePage, Problem, ProblemKind,
+    PurgedMessages, StorageError, StorageTransaction, TimeKey, TimeRange,
+};
+
+use data_encoding::BASE64URL_NOPAD;
+use foundationdb::tuple::{self, Decode, Encode};
+use foundationdb::Subspace;
+use std::collections::HashMap;
+use switchroom_grpc::proto;
+
+/// Store the single copy of `message`'s body.
+fn set_body(
+    trx: &dyn StorageTransaction,
+    message: &proto::Message,
+    encoding: &BlobEncoding,
+) -> Result<(), StorageError> {
+    use prost::Message;
+
+    let mut buf = Vec::new();
+    message.encode(&mut buf)?;
+    let received = to_integer_date(received_at(message).date());
+    set_blob(
+        trx,
+        &Subspace::from(("B", message.hash.clone())),
+        &buf,
+        &message.hash,
+        received,
+        encoding,
+    )
+}
+
+fn read_body(
+    trx: &dyn StorageTransaction,
+    hash: &[u8],
+    encoding: &BlobEncoding,
+) -> Result<Option<proto::Message>, StorageError> {
+    let (begin, end) = body_range(hash);
+    read_blob(&scan_values(trx, &begin, &end)?, hash, encoding)
+}
+
+pub fn insert_message(
+    trx: &dyn StorageTransaction,
+    message: &proto::Message,
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<InsertedMessage, StorageError> {
+    // A retried send finds its earlier copy, which is returned untouched
+    if let Some(existing) = read_entry(trx, &message.to, &message.hash, encoding)? {
+        if existing.from != message.from {
+            return Err(StorageError::HashConflict);
+        }
+        return Ok(InsertedMessage {
+            message: existing,
+            duplicate: true,
+        });
+    }
+
+    // Hashes are chosen by clients, so refuse to let a message take over a
+    // body stored for another pair of participants
+    if let Some(existing) = read_body(trx, &message.hash, encoding)? {
+        if existing.from != message.from || existing.to != message.to {
+            return Err(StorageError::HashConflict);
+        }
+    }
+
+    let timestamp = message
+        .received_at
+        .as_ref()
+        .expect("Couldn't get timestamp");
+    let expires = to_integer_date(retention.expires_at(message).date());
+
+    // Store the body once, replacing any previous copy
+    let (begin, end) = body_range(&message.hash);
+    trx.clear_range(&begin, &end);
+    set_body(trx, message, encoding)?;
+
+    // Reference it from, index it for and set expiry keys for both the `to`
+    // and `from` client IDs
+    let exp_subspace = Subspace::from(("E", expires));
+    for client_id in &[&message.to, &message.from] {
+        trx.set(
+            &mailbox_key(client_id, &message.hash),
+            &counterpart(message, client_id).to_vec(),
+        );
+        trx.set(&time_key(client_id, timestamp, &message.hash), &().to_vec());
+        trx.set(
+            &exp_subspace.pack((client_id.as_str(), message.hash.clone())),
+            &expiry_value(timestamp),
+        );
+        // Wake anyone watching the mailbox. The value only needs to differ
+        // from the last one.
+        trx.set(
+            &notify_key(client_id),
+            &(
+                timestamp.seconds,
+                i64::from(timestamp.nanos),
+                message.hash.clone(),
+            )
+                .to_vec(),
+        );
+    }
+
+    Ok(InsertedMessage {
+        message: message.clone(),
+        duplicate: false,
+    })
+}
+
+pub fn insert_messages(
+    trx: &dyn StorageTransaction,
+    messages: &[proto::Message],
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<Vec<InsertResult>, StorageError> {
+    let mut results = Vec::with_capacity(messages.len());
+    for message in messages {
+        // A conflict is found before anything is written, so the rest of
+        // the batch can go ahead
+        match insert_message(trx, message, retention, encoding) {
+            Err(StorageError::HashConflict) => results.push(Err(StorageError::HashConflict)),
+            result => results.push(Ok(result?)),
+        }
+    }
+    Ok(results)
+}
+
+/// Feed the mailbox entries in `[begin, end)` to `assembler` until it fills
+/// up.
+fn read_entries(
+    trx: &dyn StorageTransaction,
+    begin: &[u8],
+    end: &[u8],
+    assembler: &mut BlobAssembler,
+) -> Result<(), StorageError> {
+    let mut result = Ok(());
+    trx.scan(
+        begin,
+        end,
+        &mut |key, value| match assembler.push(trx, key, value) {
+            Ok(more) => more,
+            Err(err) => {
+                result = Err(err);
+                false
+            }
+        },
+    )?;
+    result
+}
+
+pub fn get_messages_for(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+    cursor: Option<Vec<u8>>,
+    filter: &dyn Fn(&[u8]) -> bool,
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<MessagePage, StorageError> {
+    let (begin, end) = mailbox_range(client_id, cursor)?;
+
+    let mut assembler = BlobAssembler::new(filter, retention, encoding);
+    read_entries(trx, &begin, &end, &mut assembler)?;
+
+    Ok(assembler.into_page())
+}
+
+pub fn export_messages(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+    include_expired: bool,
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<MessagePage, StorageError> {
+    let (begin, end) = mailbox_range(client_id, cursor)?;
+
+    let filter = |_: &[u8]| true;
+    let mut assembler =
+        BlobAssembler::new(&filter, retention, encoding).for_export(limit, include_expired);
+    read_entries(trx, &begin, &end, &mut assembler)?;
+
+    Ok(assembler.into_page())
+}
+
+pub fn get_messages_between(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+    range: &TimeRange,
+    cursor: Option<Vec<u8>>,
+    filter: &dyn Fn(&[u8]) -> bool,
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<MessagePage, StorageError> {
+    let (begin, end) = received_range(client_id, range, cursor)?;
+
+    let mut index = vec![];
+    trx.scan(&begin, &end, &mut |key, _value| {
+        if let Some(hash) = decode_time_key(key) {
+            index.push((key.to_vec(), hash));
+        }
+        true
+    })?;
+
+    let mut assembler = BlobAssembler::new(filter, retention, encoding);
+
+    for (index_key, hash) in index {
+        if filter(&hash) {
+            let (begin, end) = entry_range(client_id, &hash);
+            read_entries(trx, &begin, &end, &mut assembler)?;
+            if assembler.is_full() {
+                break;
+            }
+        }
+        assembler.resume_after(&index_key);
+    }
+
+    Ok(assembler.into_page())
+}
+
+pub fn notify_key(client_id: &str) -> Vec<u8> {
+    ("N", client_id).to_vec()
+}
+
+pub fn get_mailbox_updates(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+    since: proto::Timestamp,
+    filter: &dyn Fn(&[u8]) -> bool,
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<MailboxUpdates, StorageError> {
+    let token = trx.get(&notify_key(client_id))?;
+    let range = TimeRange {
+        since: Some(since),
+        until: None,
+    };
+    let page = get_messages_between(trx, client_id, &range, None, filter, retention, encoding)?;
+
+    Ok(MailboxUpdates { page, token })
+}
+
+/// Read the message with `hash` through `client_id`'s mailbox entry.
+fn read_entry(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+    hash: &[u8],
+    encoding: &BlobEncoding,
+) -> Result<Option<proto::Message>, StorageError> {
+    let (begin, end) = entry_range(client_id, hash);
+
+    let mut reference = false;
+    let mut values = vec![];
+    trx.scan(&begin, &end, &mut |key, value| {
+        match decode_mailbox_key(key) {
+            Some(MailboxKey::Reference { .. }) => reference = true,
+            Some(MailboxKey::LegacyChunk { .. }) => values.push(value.to_vec()),
+            None => (),
+        }
+        true
+    })?;
+
+    if reference {
+        read_body(trx, hash, encoding)
+    } else {
+        read_blob(&values, hash, encoding)
+    }
+}
+
+pub fn delete_message(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+    hash: &[u8],
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<Option<proto::Message>, StorageError> {
+    let message = match read_entry(trx, client_id, hash, encoding)? {
+        Some(message) => message,
+        None => return Ok(None),
+    };
+
+    // Deleting removes both copies, so either participant's hold blocks it
+    for participant in &[&message.to, &message.from] {
+        if is_held(trx, participant)? {
+            return Err(StorageError::LegalHold {
+                client_id: participant.to_string(),
+            });
+        }
+    }
+
+    // Clear both entries, the body, index entries and expiry keys
+    clear_keys(trx, &message_keys(&message, retention));
+
+    Ok(Some(message))
+}
+
+fn clear_keys(trx: &dyn StorageTransaction, message_keys: &MessageKeys) {
+    for (begin, end) in &message_keys.ranges {
+        trx.clear_range(begin, end);
+    }
+    for key in &message_keys.keys {
+        trx.clear(key);
+    }
+}
+
+/// Remove up to `limit` messages from `client_id`'s mailbox, along with the
+/// copies in their counterparts' mailboxes. A counterpart under legal hold
+/// keeps its copy. Returns what was removed, and whether the mailbox may
+/// still hold more.
+pub fn purge_client(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+    limit: usize,
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<(PurgedMessages, bool), StorageError> {
+    use prost::Message;
+
+    if is_held(trx, client_id)? {
+        return Err(StorageError::LegalHold {
+            client_id: client_id.to_string(),
+        });
+    }
+
+    let (begin, end) = Subspace::from(("M", client_id)).range();
+    let mut hashes: Vec<Vec<u8>> = vec![];
+    let mut more = false;
+    trx.scan(&begin, &end, &mut |key, _value| {
+        let hash = match decode_mailbox_key(key) {
+            Some(MailboxKey::Reference { hash, .. }) => hash,
+            Some(MailboxKey::LegacyChunk { hash, .. }) => hash,
+            None => return true,
+        };
+        if hashes.last() != Some(&hash) {
+            if hashes.len() == limit {
+                more = true;
+                return false;
+            }
+            hashes.push(hash);
+        }
+        true
+    })?;
+
+    let mut purged = PurgedMessages::default();
+    for hash in &hashes {
+        let message = match read_entry(trx, client_id, hash, encoding) {
+            Ok(Some(message)) => message,
+            result => {
+                // Nothing to find the counterpart with, so just drop the entry
+                error!("purging unreadable entry {:?}: {:?}", hash, result);
+                clear_entry(trx, client_id, hash)?;
+                continue;
+            }
+        };
+
+        let other = counterpart(&message, client_id);
+        if other != client_id && is_held(trx, other)? {
+            clear_keys(trx, &entry_keys(&message, client_id, retention));
+            purged.held += 1;
+        } else {
+            clear_keys(trx, &message_keys(&message, retention));
+            purged.messages += 1;
+            purged.bytes += message.encoded_len();
+        }
+    }
+
+    // Sweep up anything left which didn't decode
+    if !more {
+        trx.clear_range(&begin, &end);
+        let (begin, end) = Subspace::from(("T", client_id)).range();
+        trx.clear_range(&begin, &end);
+        trx.clear(&notify_key(client_id));
+    }
+
+    Ok((purged, more))
+}
+
+/// Remove `client_id`'s entry for `hash`, along with the body once no other
+/// mailbox references it.
+fn clear_entry(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+    hash: &[u8],
+) -> Result<(), StorageError> {
+    let reference = trx.get(&mailbox_key(client_id, hash))?;
+
+    let (begin, end) = entry_range(client_id, hash);
+    trx.clear_range(&begin, &end);
+
+    // Legacy entries have no reference, and no shared body
+    if let Some(value) = reference {
+        let result: tuple::Result<String> = Decode::try_from(&value);
+        match result {
+            Ok(counterpart) => {
+                if trx.get(&mailbox_key(&counterpart, hash))?.is_none() {
+                    let (begin, end) = body_range(hash);
+                    trx.clear_range(&begin, &end);
+                }
+            }
+            Err(err) => error!("error decoding mailbox entry: {:?}", err),
+        }
+    }
+    Ok(())
+}
+
+/// Collect expiry keys under `prefix` dated before `before` and after
+/// `cursor`, until `exp_kvs` holds `limit` of them.
+fn scan_expiry_keys(
+    trx: &dyn StorageTransaction,
+    prefix: &str,
+    before: i64,
+    cursor: &Option<Vec<u8>>,
+    limit: usize,
+    exp_kvs: &mut Vec<(Vec<u8>, Vec<u8>)>,
+) -> Result<(), StorageError> {
+    if exp_kvs.len() >= limit {
+        return Ok(());
+    }
+    let mut start = (prefix, 0).to_vec();
+    let end = (prefix, before).to_vec();
+    if let Some(cursor) = cursor {
+        // The smallest key greater than the cursor
+        let mut after = cursor.clone();
+        after.push(0);
+        start = std::cmp::max(start, after);
+    }
+    if start >= end {
+        return Ok(());
+    }
+    trx.scan(&start, &end, &mut |key, value| {
+        exp_kvs.push((key.to_vec(), value.to_vec()));
+        exp_kvs.len() < limit
+    })
+}
+
+/// Clear up to `limit` entries which expired before `expiry`, or which were
+/// received before `legacy_expiry` for those with legacy expiry keys. Entries
+/// of clients under legal hold are kept, and counted in the result. Each
+/// cleared entry's expiry key is removed with it, so the returned cursor only
+/// needs to step past held entries.
+pub fn clear_expired(
+    trx: &dyn StorageTransaction,
+    expiry: i64,
+    legacy_expiry: i64,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+) -> Result<(ClearedExpired, Option<Vec<u8>>), StorageError> {
+    let mut exp_kvs = vec![];
+    scan_expiry_keys(trx, "E", expiry, &cursor, limit, &mut exp_kvs)?;
+    scan_expiry_keys(trx, "R", legacy_expiry, &cursor, limit, &mut exp_kvs)?;
+
+    let mut result = ClearedExpired::default();
+    let mut holds = HashMap::new();
+    for (key, value) in &exp_kvs {
+        let decoded: tuple::Result<ExpKey> = Decode::try_from(key);
+        match decoded {
+            Ok((_prefix, _expiry, client_id, hash)) => {
+                if !holds.contains_key(&client_id) {
+                    let held = is_held(trx, &client_id)?;
+                    holds.insert(client_id.clone(), held);
+                }
+                if holds[&client_id] {
+                    *result.held.entry(client_id).or_insert(0) += 1;
+                    continue;
+                }
+                // Clear this entry and its index entry
+                if let Some(time_key) = expired_time_key(&client_id, &hash, value) {
+                    trx.clear(&time_key);
+                }
+                clear_entry(trx, &client_id, &hash)?;
+                result.cleared += 1;
+            }
+            Err(err) => {
+                error!("error decoding key: {:?}", err);
+            }
+        }
+        // Clear the expiry key, even if it couldn't be decoded
+        trx.clear(key);
+    }
+
+    let cursor = if exp_kvs.len() == limit {
+        exp_kvs.last().map(|(key, _value)| key.clone())
+    } else {
+        None
+    };
+    Ok((result, cursor))
+}
+
+fn legal_hold_key(client_id: &str) -> Vec<u8> {
+    ("H", client_id).to_vec()
+}
+
+/// Whether `client_id` is under legal hold.
+fn is_held(trx: &dyn StorageTransaction, client_id: &str) -> Result<bool, StorageError> {
+    Ok(trx.get(&legal_hold_key(client_id))?.is_some())
+}
+
+/// Place `hold` on its client, replacing any hold already in place.
+pub fn add_legal_hold(
+    trx: &dyn StorageTransaction,
+    hold: &proto::LegalHold,
+) -> Result<(), StorageError> {
+    use prost::Message;
+
+    let mut buf = Vec::new();
+    hold.encode(&mut buf)?;
+    trx.set(&legal_hold_key(&hold.client_id), &buf);
+    Ok(())
+}
+
+/// Lift the hold on `client_id`, returning whether there was one.
+pub fn remove_legal_hold(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+) -> Result<bool, StorageError> {
+    let held = is_held(trx, client_id)?;
+    trx.clear(&legal_hold_key(client_id));
+    Ok(held)
+}
+
+pub fn list_legal_holds(
+    trx: &dyn StorageTransaction,
+) -> Result<Vec<proto::LegalHold>, StorageError> {
+    use prost::Message;
+
+    let (begin, end) = Subspace::from("H").range();
+    scan_values(trx, &begin, &end)?
+        .iter()
+        .map(|value| Ok(proto::LegalHold::decode(value)?))
+        .collect()
+}
+
+/// The chunks of one legacy mailbox entry.
+struct LegacyEntry {
+    client_id: String,
+    hash: Vec<u8>,
+    values: Vec<Vec<u8>>,
+    last_key: Vec<u8>,
+}
+
+pub fn migrate_legacy_blobs(
+    trx: &dyn StorageTransaction,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+    encoding: &BlobEncoding,
+) -> Result<(usize, Option<Vec<u8>>), StorageError> {
+    let subspace = Subspace::from("M");
+    let (begin, end) = subspace.range();
+    let begin = resume_from(&subspace, begin, cursor)?;
+
+    let mut entries: Vec<LegacyEntry> = vec![];
+    let mut more = false;
+    trx.scan(&begin, &end, &mut |key, value| {
+        if let Some(MailboxKey::LegacyChunk { client_id, hash }) = decode_mailbox_key(key) {
+            let same_entry = match entries.last() {
+                Some(entry) => entry.client_id == client_id && entry.hash == hash,
+                None => false,
+            };
+            if !same_entry {
+                if entries.len() == limit {
+                    more = true;
+                    return false;
+                }
+                entries.push(LegacyEntry {
+                    client_id,
+                    hash,
+                    values: vec![],
+                    last_key: vec![],
+                });
+            }
+            let entry = entries.last_mut().unwrap();
+            entry.values.push(value.to_vec());
+            entry.last_key = key.to_vec();
+        }
+        true
+    })?;
+
+    for entry in &entries {
+        let message = match read_blob(&entry.values, &entry.hash, encoding) {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            Err(err) => {
+                error!("skipping undecodable legacy entry: {:?}", err);
+                continue;
+            }
+        };
+
+        // The counterpart's copy may already have been migrated
+        if read_body(trx, &entry.hash, encoding)?.is_none() {
+            set_body(trx, &message, encoding)?;
+        }
+        let (begin, end) =
+            Subspace::from(("M", entry.client_id.as_str(), entry.hash.clone())).range();
+        trx.clear_range(&begin, &end);
+        trx.set(
+            &mailbox_key(&entry.client_id, &entry.hash),
+            &counterpart(&message, &entry.client_id).to_vec(),
+        );
+    }
+
+    let cursor = if more {
+        entries.last().map(|entry| entry.last_key.clone())
+    } else {
+        None
+    };
+    Ok((entries.len(), cursor))
+}
+
+fn schema_version_key() -> Vec<u8> {
+    "V".to_vec()
+}
+
+pub fn schema_version(trx: &dyn StorageTransaction) -> Result<i64, StorageError> {
+    match trx.get(&schema_version_key())? {
+        Some(value) => {
+            let version: tuple::Result<i64> = Decode::try_from(&value);
+            Ok(version?)
+        }
+        None => Ok(0),
+    }
+}
+
+pub fn set_schema_version(trx: &dyn StorageTransaction, version: i64) {
+    trx.set(&schema_version_key(), &version.to_vec());
+}
+
+/// Refile up to `limit` legacy expiry keys, which are filed under the
+/// received date, under the date they expire. They keep the default
+/// retention they were written for, rather than taking their recipient's.
+/// Keys for entries which can't be read are left alone, while keys for
+/// entries which have gone are dropped.
+pub fn migrate_legacy_expiry_keys(
+    trx: &dyn StorageTransaction,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<(usize, Option<Vec<u8>>), StorageError> {
+    let subspace = Subspace::from("R");
+    let (begin, end) = subspace.range();
+    let begin = resume_from(&subspace, begin, cursor)?;
+
+    let mut keys = vec![];
+    trx.scan(&begin, &end, &mut |key, _value| {
+        keys.push(key.to_vec());
+        keys.len() < limit
+    })?;
+
+    let mut migrated = 0;
+    for key in &keys {
+        let decoded: tuple::Result<ExpKey> = Decode::try_from(key);
+        let (client_id, hash) = match decoded {
+            Ok((_prefix, _received, client_id, hash)) => (client_id, hash),
+            Err(err) => {
+                error!("skipping undecodable legacy expiry key: {:?}", err);
+                continue;
+            }
+        };
+        match read_entry(trx, &client_id, &hash, encoding) {
+            Ok(Some(message)) => {
+                let timestamp = message
+                    .received_at
+                    .as_ref()
+                    .expect("Couldn't get timestamp");
+                let expires =
+                    received_at(&message) + chrono::Duration::days(retention.default_days());
+                trx.set(
+                    &(
+                        "E",
+                        to_integer_date(expires.date()),
+                        client_id.as_str(),
+                        hash,
+                    )
+                        .to_vec(),
+                    &expiry_value(timestamp),
+                );
+                migrated += 1;
+            }
+            Ok(None) => (),
+            Err(err) => {
+                error!("skipping legacy expiry key of unreadable entry: {:?}", err);
+                continue;
+            }
+        }
+        trx.clear(key);
+    }
+
+    let cursor = if keys.len() == limit {
+        keys.last().cloned()
+    } else {
+        None
+    };
+    Ok((migrated, cursor))
+}
+
+/// The chunks of one stored body.
+struct StoredBody {
+    hash: Vec<u8>,
+    values: Vec<Vec<u8>>,
+    last_key: Vec<u8>,
+}
+
+pub fn reencrypt_blobs(
+    trx: &dyn StorageTransaction,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+    encoding: &BlobEncoding,
+) -> Result<(usize, Option<Vec<u8>>), StorageError> {
+    use prost::Message;
+
+    let subspace = Subspace::from("B");
+    let (begin, end) = subspace.range();
+    let begin = resume_from(&subspace, begin, cursor)?;
+
+    let mut bodies: Vec<StoredBody> = vec![];
+    let mut more = false;
+    trx.scan(&begin, &end, &mut |key, value| {
+        let result: tuple::Result<BodyKey> = Decode::try_from(key);
+        match result {
+            Ok((_prefix, hash, _offset)) => {
+                let same_body = match bodies.last() {
+                    Some(body) => body.hash == hash,
+                    None => false,
+                };
+                if !same_body {
+                    if bodies.len() == limit {
+                        more = true;
+                        return false;
+                    }
+                    bodies.push(StoredBody {
+                        hash,
+                        values: vec![],
+                        last_key: vec![],
+                    });
+                }
+                let body = bodies.last_mut().unwrap();
+                body.values.push(value.to_vec());
+                body.last_key = key.to_vec();
+            }
+            Err(err) => error!("failed to decode body key: {:?}", err),
+        }
+        true
+    })?;
+
+    let mut rewritten = 0;
+    for body in &bodies {
+        let key_id = proto::BlobValue::decode(&body.values[0])?.key_id;
+        if !encoding.is_stale(&key_id) {
+            continue;
+        }
+        match read_blob(&body.values, &body.hash, encoding) {
+            Ok(Some(message)) => {
+                let (begin, end) = body_range(&body.hash);
+                trx.clear_range(&begin, &end);
+                set_body(trx, &message, encoding)?;
+                rewritten += 1;
+            }
+            Ok(None) => (),
+            Err(err) => error!("skipping unreadable body {:?}: {:?}", body.hash, err),
+        }
+    }
+
+    let cursor = if more {
+        bodies.last().map(|body| body.last_key.clone())
+    } else {
+        None
+    };
+    Ok((rewritten, cursor))
+}
+
+/// The subspaces `check_integrity` walks, in order. Bodies come before the
+/// mailbox entries which reference them, and entries before the index
+/// entries and expiry keys which point at them, so that keys left behind by a
+/// repair are found later in the same pass.
+const CHECKED_SUBSPACES: [&str; 5] = ["B", "M", "T", "E", "R"];
+
+/// Keys checked together: the chunks of a body or of a legacy mailbox entry,
+/// or a single key otherwise.
+struct KeyGroup {
+    id: Vec<u8>,
+    kvs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+fn key_group_id(prefix: &str, key: &[u8]) -> Vec<u8> {
+    match prefix {
+        "B" => {
+            let result: tuple::Result<BodyKey> = Decode::try_from(key);
+            if let Ok((_prefix, hash, _offset)) = result {
+                return body_range(&hash).0;
+            }
+        }
+        "M" => match decode_mailbox_key(key) {
+            Some(MailboxKey::Reference { client_id, hash })
+            | Some(MailboxKey::LegacyChunk { client_id, hash }) => {
+                return mailbox_key(&client_id, &hash);
+            }
+            None => (),
+        },
+        _ => (),
+    }
+    key.to_vec()
+}
+
+/// Collect the key groups in `[begin, end)` until they hold at least `limit`
+/// keys. Returns the groups, and whether there may be more.
+fn scan_key_groups(
+    trx: &dyn StorageTransaction,
+    prefix: &str,
+    begin: &[u8],
+    end: &[u8],
+    limit: usize,
+) -> Result<(Vec<KeyGroup>, bool), StorageError> {
+    let mut groups: Vec<KeyGroup> = vec![];
+    let mut keys = 0;
+    let mut more = false;
+    trx.scan(begin, end, &mut |key, value| {
+        let id = key_group_id(prefix, key);
+        let same_group = match groups.last() {
+            Some(group) => group.id == id,
+            None => false,
+        };
+        if !same_group {
+            if keys >= limit {
+                more = true;
+                return false;
+            }
+            groups.push(KeyGroup { id, kvs: vec![] });
+        }
+        groups
+            .last_mut()
+            .unwrap()
+            .kvs
+            .push((key.to_vec(), value.to_vec()));
+        keys += 1;
+        true
+    })?;
+    Ok((groups, more))
+}
+
+/// Check the chunks of the blob stored for `hash`, returning the message
+/// they hold or what's wrong with them. A missing encryption key is an error
+/// rather than a problem, since every encrypted blob would look broken.
+fn check_blob(
+    values: &[Vec<u8>],
+    hash: &[u8],
+    encoding: &BlobEncoding,
+) -> Result<Result<proto::Message, (ProblemKind, String)>, StorageError> {
+    use prost::Message;
+
+    let mut length = 0;
+    let mut blob_length = None;
+    for (i, value) in values.iter().enumerate() {
+        let blob_value = match proto::BlobValue::decode(value) {
+            Ok(blob_value) => blob_value,
+            Err(err) => {
+                return Ok(Err((
+                    ProblemKind::UndecodableBlob,
+                    format!("chunk {} doesn't decode: {}", i, err),
+                )));
+            }
+        };
+        if blob_value.blob_chunk != i as i64 {
+            return Ok(Err((
+                ProblemKind::IncompleteBlob,
+                format!(
+                    "expected chunk {}, found chunk {}",
+                    i, blob_value.blob_chunk
+                ),
+            )));
+        }
+        if *blob_length.get_or_insert(blob_value.blob_length) != blob_value.blob_length {
+            return Ok(Err((
+                ProblemKind::IncompleteBlob,
+                "chunks disagree on blob_length".to_string(),
+            )));
+        }
+        length += blob_value.payload.len() as i64;
+    }
+    let blob_length = blob_length.unwrap_or_default();
+    if length != blob_length {
+        return Ok(Err((
+            ProblemKind::IncompleteBlob,
+            format!("chunks hold {} of {} bytes", length, blob_length),
+        )));
+    }
+
+    match read_blob(values, hash, encoding) {
+        Ok(Some(message)) => {
+            if message.hash == hash {
+                Ok(Ok(message))
+            } else {
+                Ok(Err((
+                    ProblemKind::UndecodableBlob,
+                    format!(
+                        "holds the message with hash {}",
+                        BASE64URL_NOPAD.encode(&message.hash)
+                    ),
+                )))
+            }
+        }
+        Ok(None) => Ok(Err((ProblemKind::IncompleteBlob, "no chunks".to_string()))),
+        Err(err @ StorageError::KeyringError { .. }) => Err(err),
+        Err(err) => Ok(Err((ProblemKind::UndecodableBlob, err.to_string()))),
+    }
+}
+
+/// Whether `client_id` has an entry for `hash`, in either layout.
+fn has_entry(
+    trx: &dyn StorageTransaction,
+    client_id: &str,
+    hash: &[u8],
+) -> Result<bool, StorageError> {
+    let (begin, end) = entry_range(client_id, hash);
+    let mut found = false;
+    trx.scan(&begin, &end, &mut |_key, _value| {
+        found = true;
+        false
+    })?;
+    Ok(found)
+}
+
+fn is_participant(message: &proto::Message, client_id: &str) -> bool {
+    message.to == client_id || message.from == client_id
+}
+
+/// Check a body's chunks, and that both participants' mailboxes reference
+/// it. A broken body is removed on repair, leaving the entries referencing it
+/// dangling.
+fn check_body(
+    trx: &dyn StorageTransaction,
+    group: &KeyGroup,
+    repair: bool,
+    retention: &Retention,
+    encoding: &BlobEncoding,
+    report: &mut IntegrityReport,
+) -> Result<(), StorageError> {
+    let result: tuple::Result<BodyKey> = Decode::try_from(&group.kvs[0].0);
+    let hash = match result {
+        Ok((_prefix, hash, _offset)) => hash,
+        Err(_) => {
+            report_undecodable_keys(group, report);
+            return Ok(());
+        }
+    };
+
+    let values: Vec<Vec<u8>> = group
+        .kvs
+        .iter()
+        .map(|(_key, value)| value.clone())
+        .collect();
+    let message = match check_blob(&values, &hash, encoding)? {
+        Ok(message) => message,
+        Err((kind, detail)) => {
+            if repair {
+                let (begin, end) = body_range(&hash);
+                trx.clear_range(&begin, &end);
+            }
+            report.problems.push(Problem {
+                kind,
+                detail: format!("body {}: {}", BASE64URL_NOPAD.encode(&hash), detail),
+                repaired: repair,
+            });
+            return Ok(());
+        }
+    };
+
+    let mut participants = vec![&message.to];
+    if message.from != message.to {
+        participants.push(&message.from);
+    }
+    let mut referenced_by = vec![];
+    for client_id in participants.iter() {
+        if trx.get(&mailbox_key(client_id, &hash))?.is_some() {
+            referenced_by.push(client_id);
+        }
+    }
+
+    if referenced_by.is_empty() {
+        if repair {
+            clear_keys(trx, &message_keys(&message, retention));
+        }
+        report.problems.push(Problem {
+            kind: ProblemKind::OrphanedBody,
+            detail: format!(
+                "body {} from {} to {} isn't in either mailbox",
+                BASE64URL_NOPAD.encode(&hash),
+                message.from,
+                message.to
+            ),
+            repaired: repair,
+        });
+    } else if referenced_by.len() < participants.len() {
+        report.problems.push(Problem {
+            kind: ProblemKind::UnpairedEntry,
+            detail: format!(
+                "body {} from {} to {} is only in {}'s mailbox",
+                BASE64URL_NOPAD.encode(&hash),
+                message.from,
+                message.to,
+                referenced_by[0]
+            ),
+            repaired: false,
+        });
+    }
+    Ok(())
+}
+
+/// Check a mailbox entry: that a reference points at a stored body and holds
+/// the other participant, or that a legacy entry's own chunks are readable.
+fn check_entry(
+    trx: &dyn StorageTransaction,
+    group: &KeyGroup,
+    repair: bool,
+    encoding: &BlobEncoding,
+    report: &mut IntegrityReport,
+) -> Result<(), StorageError> {
+    let (client_id, hash, legacy) = match decode_mailbox_key(&group.kvs[0].0) {
+        Some(MailboxKey::Reference { client_id, hash }) => (client_id, hash, false),
+        Some(MailboxKey::LegacyChunk { client_id, hash }) => (client_id, hash, true),
+        None => {
+            report_undecodable_keys(group, report);
+            return Ok(());
+        }
+    };
+    let describe = |detail: &str| {
+        format!(
+            "entry {} in {}'s mailbox {}",
+            BASE64URL_NOPAD.encode(&hash),
+            client_id,
+            detail
+        )
+    };
+    let (begin, end) = entry_range(&client_id, &hash);
+
+    let message = if legacy {
+        let values: Vec<Vec<u8>> = group
+            .kvs
+            .iter()
+            .map(|(_key, value)| value.clone())
+            .collect();
+        match check_blob(&values, &hash, encoding)? {
+            Ok(message) => message,
+            Err((kind, detail)) => {
+                if repair {
+                    trx.clear_range(&begin, &end);
+                }
+                report.problems.push(Problem {
+                    kind,
+                    detail: describe(&detail),
+                    repaired: repair,
+                });
+                return Ok(());
+            }
+        }
+    } else {
+        let (body_begin, body_end) = body_range(&hash);
+        let values = scan_values(trx, &body_begin, &body_end)?;
+        if values.is_empty() {
+            if repair {
+                trx.clear_range(&begin, &end);
+            }
+            report.problems.push(Problem {
+                kind: ProblemKind::DanglingEntry,
+                detail: describe("references a body which isn't stored"),
+                repaired: repair,
+            });
+            return Ok(());
+        }
+        match read_blob(&values, &hash, encoding) {
+            Ok(Some(message)) => message,
+            // Broken bodies are reported when they're checked
+            _ => return Ok(()),
+        }
+    };
+
+    if !is_participant(&message, &client_id) {
+        if repair {
+            trx.clear_range(&begin, &end);
+        }
+        report.problems.push(Problem {
+            kind: ProblemKind::MismatchedEntry,
+            detail: describe(&format!(
+                "holds a message from {} to {}",
+                message.from, message.to
+            )),
+            repaired: repair,
+        });
+    } else if !legacy {
+        let expected = counterpart(&message, &client_id).to_vec();
+        if group.kvs[0].1 != expected {
+            if repair {
+                trx.set(&group.kvs[0].0, &expected);
+            }
+            report.problems.push(Problem {
+                kind: ProblemKind::MismatchedEntry,
+                detail: describe(&format!(
+                    "doesn't hold its counterpart {}",
+                    counterpart(&message, &client_id)
+                )),
+                repaired: repair,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Check that the client and hash an index entry or expiry key was written
+/// for still have a mailbox entry.
+fn check_pointer(
+    trx: &dyn StorageTransaction,
+    prefix: &str,
+    key: &[u8],
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Result<bool, StorageError> {
+    let (kind, client_id, hash) = if prefix == "T" {
+        let result: tuple::Result<TimeKey> = Decode::try_from(key);
+        match result {
+            Ok((_prefix, client_id, _seconds, _nanos, hash)) => {
+                (ProblemKind::OrphanedIndexEntry, client_id, hash)
+            }
+            Err(_) => return Ok(false),
+        }
+    } else {
+        let result: tuple::Result<ExpKey> = Decode::try_from(key);
+        match result {
+            Ok((_prefix, _date, client_id, hash)) => {
+                (ProblemKind::OrphanedExpiryKey, client_id, hash)
+            }
+            Err(_) => return Ok(false),
+        }
+    };
+
+    if !has_entry(trx, &client_id, &hash)? {
+        if repair {
+            trx.clear(key);
+        }
+        report.problems.push(Problem {
+            kind,
+            detail: format!(
+                "{} key for {} in {}'s mailbox, which has no entry",
+                prefix,
+                BASE64URL_NOPAD.encode(&hash),
+                client_id
+            ),
+            repaired: repair,
+        });
+    }
+    Ok(true)
+}
+
+fn report_undecodable_keys(group: &KeyGroup, report: &mut IntegrityReport) {
+    for (key, _value) in &group.kvs {
+        report.problems.push(Problem {
+            kind: ProblemKind::UndecodableKey,
+            detail: format!("key {} doesn't decode", BASE64URL_NOPAD.encode(key)),
+            repaired: false,
+        });
+    }
+}
+
+/// Check up to `limit` keys after `cursor`, walking the bodies, mailbox
+/// entries, index entries and expiry keys in turn. A body or legacy entry's
+/// chunks are always checked together, so a batch can run over `limit`.
+pub fn check_integrity(
+    trx: &dyn StorageTransaction,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+    repair: bool,
+    retention: &Retention,
+    encoding: &BlobEncoding,
+) -> Result<(IntegrityReport, Option<Vec<u8>>), StorageError> {
+    let first = match cursor {
+        Some(ref cursor) => CHECKED_SUBSPACES
+            .iter()
+            .position(|prefix| {
+                let (begin, end) = Subspace::from(*prefix).range();
+                *cursor >= begin && *cursor < end
+            })
+            .ok_or(StorageError::InvalidCursor)?,
+        None => 0,
+    };
+
+    let mut report = IntegrityReport::default();
+    let mut cursor = cursor;
+    let mut last_key = None;
+    for prefix in &CHECKED_SUBSPACES[first..] {
+        if report.keys_checked >= limit {
+            return Ok((report, last_key));
+        }
+
+        let subspace = Subspace::from(*prefix);
+        let (begin, end) = subspace.range();
+        let begin = resume_from(&subspace, begin, cursor.take())?;
+        let (groups, more) =
+            scan_key_groups(trx, prefix, &begin, &end, limit - report.keys_checked)?;
+
+        for group in &groups {
+            report.keys_checked += group.kvs.len();
+            match *prefix {
+                "B" => check_body(trx, group, repair, retention, encoding, &mut report)?,
+                "M" => check_entry(trx, group, repair, encoding, &mut report)?,
+                _ => {
+                    if !check_pointer(trx, prefix, &group.kvs[0].0, repair, &mut report)? {
+                        report_undecodable_keys(group, &mut report);
+                    }
+                }
+            }
+            last_key = group.kvs.last().map(|(key, _value)| key.clone());
+        }
+
+        if more {
+            return Ok((report, last_key));
+        }
+    }
+    Ok((report, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::Timestamped;
+    use crate::storage::memory::MemoryDB;
+    use crate::storage::{MessageStore, TransactionalStore};
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::storage::keyring::Keyring;
+    use crate::storage::testing::{self, make_message};
+    use futures::Future;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use switchroom_grpc::proto::blob_value::Codec;
+
+    fn scan_keys(db: &MemoryDB, begin: &[u8], end: &[u8]) -> Vec<Vec<u8>> {
+        let (begin, end) = (begin.to_vec(), end.to_vec());
+        db.transact(move |trx| {
+            let mut keys = vec![];
+            trx.scan(&begin, &end, &mut |key, _value| {
+                keys.push(key.to_vec());
+                true
+            })?;
+            Ok(keys)
+        })
+        .wait()
+        .unwrap()
+    }
+
+    /// Write `message` in the layout used before bodies were shared, with a
+    /// copy of the blob in each participant's mailbox.
+    fn insert_legacy_message(db: &MemoryDB, message: proto::Message) {
+        use prost::Message;
+
+        db.transact(move |trx| {
+            let mut buf = Vec::new();
+            message.encode(&mut buf)?;
+            let timestamp = message.received_at.clone().unwrap();
+            let received = to_integer_date(received_at(&message).date());
+            for client_id in &[&message.to, &message.from] {
+                let subspace = Subspace::from(("M", client_id.as_str(), message.hash.clone()));
+                set_blob(
+                    trx,
+                    &subspace,
+                    &buf,
+                    &message.hash,
+                    received,
+                    &BlobEncoding::default(),
+                )?;
+                trx.set(
+                    &time_key(client_id, &timestamp, &message.hash),
+                    &().to_vec(),
+                );
+                trx.set(
+                    &("R", received, client_id.as_str(), message.hash.clone()).to_vec(),
+                    &expiry_value(&timestamp),
+                );
+            }
+            Ok(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn response_size_limit_test() {
+        let db = MemoryDB::new(1);
+
+        // 12 messages of 1 MiB each will not fit in a single 10 MiB response
+        for n in 0..12 {
+            let message = make_message(
+                &format!("hash{:02}", n),
+                "from id",
+                "to id",
+                vec![0u8; 1_048_576],
+            )
+            .timestamped();
+            db.insert_message(message).wait().unwrap();
+        }
+
+        let result = db
+            .get_messages_for("to id", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result.len() > 0, true);
+        assert_eq!(result.len() < 12, true);
+        assert_eq!(result[0].hash, b"hash00");
+    }
+
+    #[test]
+    fn export_messages_test() {
+        let db = MemoryDB::new(1);
+
+        // More than fits in a response, and a couple of expired messages
+        for n in 0..12 {
+            let message = make_message(
+                &format!("hash{:02}", n),
+                "from id",
+                "to id",
+                vec![0u8; 1_048_576],
+            )
+            .timestamped();
+            db.insert_message(message).wait().unwrap();
+        }
+        for n in 12..14 {
+            let message = make_message(&format!("hash{:02}", n), "from id", "to id", vec![1]);
+            db.insert_message(message).wait().unwrap();
+        }
+
+        for (include_expired, total) in &[(false, 12), (true, 14)] {
+            let mut pages = vec![];
+            let mut cursor = None;
+            loop {
+                let page = db
+                    .export_messages("to id", cursor, 5, *include_expired)
+                    .wait()
+                    .unwrap();
+                pages.push(page.messages.len());
+                cursor = page.cursor;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+            assert_eq!(pages.iter().sum::<usize>(), *total);
+            assert_eq!(pages[0], 5);
+        }
+    }
+
+    fn minutes_ago(minutes: i64) -> proto::Timestamp {
+        use chrono::prelude::*;
+        let time = Utc::now() - chrono::Duration::minutes(minutes);
+        proto::Timestamp {
+            seconds: time.timestamp(),
+            nanos: 0,
+        }
+    }
+
+    #[test]
+    fn time_range_test() {
+        let db = MemoryDB::new(1);
+
+        for minutes in &[50, 40, 30, 20, 10] {
+            let mut message = make_message(
+                &format!("hash{}", minutes),
+                "from id",
+                "to id",
+                vec![1, 2, 3],
+            );
+            message.received_at = Some(minutes_ago(*minutes));
+            db.insert_message(message).wait().unwrap();
+        }
+
+        let since = TimeRange {
+            since: Some(minutes_ago(35)),
+            until: None,
+        };
+        let result = db
+            .get_messages_between("to id", since, None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        let hashes: Vec<Vec<u8>> = result.into_iter().map(|m| m.hash).collect();
+        assert_eq!(
+            hashes,
+            vec![b"hash30".to_vec(), b"hash20".to_vec(), b"hash10".to_vec()]
+        );
+
+        let between = TimeRange {
+            since: Some(minutes_ago(45)),
+            until: Some(minutes_ago(25)),
+        };
+        let result = db
+            .get_messages_between("from id", between, None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        let hashes: Vec<Vec<u8>> = result.into_iter().map(|m| m.hash).collect();
+        assert_eq!(hashes, vec![b"hash40".to_vec(), b"hash30".to_vec()]);
+    }
+
+    #[test]
+    fn time_range_pagination_test() {
+        let db = MemoryDB::new(1);
+
+        for n in 0..12 {
+            let mut message = make_message(
+                &format!("hash{:02}", n),
+                "from id",
+                "to id",
+                vec![0u8; 1_048_576],
+            );
+            message.received_at = Some(minutes_ago(30 - n));
+            db.insert_message(message).wait().unwrap();
+        }
+
+        let range = TimeRange {
+            since: Some(minutes_ago(60)),
+            until: None,
+        };
+        let first = db
+            .get_messages_between("to id", range.clone(), None, Box::new(|_| true))
+            .wait()
+            .unwrap();
+        assert_eq!(first.cursor.is_some(), true);
+
+        let second = db
+            .get_messages_between("to id", range, first.cursor, Box::new(|_| true))
+            .wait()
+            .unwrap();
+        assert_eq!(second.cursor, None);
+        assert_eq!(first.messages.len() + second.messages.len(), 12);
+    }
+
+    #[test]
+    fn mailbox_updates_test() {
+        let db = MemoryDB::new(1);
+
+        let mut message = make_message("hash1", "from id", "to id", vec![1, 2, 3]);
+        message.received_at = Some(minutes_ago(10));
+        db.insert_message(message).wait().unwrap();
+
+        let updates = db
+            .get_mailbox_updates("to id", minutes_ago(20), Box::new(|_| true))
+            .wait()
+            .unwrap();
+        assert_eq!(updates.page.messages.len(), 1);
+        assert_eq!(updates.token.is_some(), true);
+
+        // The next insert for the client changes the token
+        let watch = db.watch_mailbox("to id", updates.token.clone());
+        let mut message = make_message("hash2", "from id", "to id", vec![1, 2, 3]);
+        message.received_at = Some(minutes_ago(5));
+        db.insert_message(message).wait().unwrap();
+        watch.wait().unwrap();
+
+        let filter = Box::new(|hash: &[u8]| hash != b"hash1");
+        let next = db
+            .get_mailbox_updates("to id", minutes_ago(20), filter)
+            .wait()
+            .unwrap();
+        let hashes: Vec<Vec<u8>> = next.page.messages.into_iter().map(|m| m.hash).collect();
+        assert_eq!(hashes, vec![b"hash2".to_vec()]);
+        assert_ne!(next.token, updates.token);
+
+        // A stale token resolves straight away
+        db.watch_mailbox("to id", updates.token).wait().unwrap();
+    }
+
+    #[test]
+    fn delete_message_test() {
+        let db = MemoryDB::new(1);
+
+        let message = make_message("hash", "from id", "to id", vec![0u8; 40000]).timestamped();
+        db.insert_message(message.clone()).wait().unwrap();
+
+        // Someone who isn't a participant can't see the message
+        let deleted = db
+            .delete_message("someone else", b"hash".to_vec())
+            .wait()
+            .unwrap();
+        assert_eq!(deleted, None);
+
+        let deleted = db.delete_message("to id", b"hash".to_vec()).wait().unwrap();
+        assert_eq!(deleted, Some(message));
+
+        // Nothing should remain for either participant
+        let (begin, end) = body_range(b"hash");
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+        for subspace in &["M", "T"] {
+            for client_id in &["from id", "to id"] {
+                let (begin, end) = Subspace::from((*subspace, *client_id)).range();
+                assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+            }
+        }
+        for subspace in &["E", "R"] {
+            let (begin, end) = Subspace::from(*subspace).range();
+            assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+        }
+
+        let deleted = db
+            .delete_message("from id", b"hash".to_vec())
+            .wait()
+            .unwrap();
+        assert_eq!(deleted, None);
+    }
+
+    #[test]
+    fn foreign_cursor_test() {
+        let db = MemoryDB::new(1);
+
+        let cursor = Subspace::from(("M", "someone else")).pack(0);
+        let result = db
+            .get_messages_for("to id", Some(cursor), Box::new(|_| true))
+            .wait();
+        assert_eq!(result.is_err(), true);
+    }
+
+    #[test]
+    fn expired_keys_test() {
+        let db = MemoryDB::new(1);
+        let (expired, not_expired) = testing::check_expired_keys(&db);
+
+        // Only the unexpired entries, bodies, index entries and expiry keys
+        // should remain
+        let (begin, end) = Subspace::from("B").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 10);
+        let (begin, end) = Subspace::from(("M", expired.as_str())).range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+        let (begin, end) = Subspace::from(("T", expired.as_str())).range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+        let (begin, end) = Subspace::from(("T", not_expired.as_str())).range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 10);
+    }
+
+    #[test]
+    fn expired_batches_test() {
+        let db = MemoryDB::new(1);
+        let n = 10;
+
+        for i in 0..n {
+            let message = make_message(&format!("hash {}", i), "expired", "nowhere", vec![1]);
+            db.insert_message(message).wait().unwrap();
+        }
+
+        // Each message has an entry in both mailboxes, and every batch but
+        // the last is full
+        let mut batches = vec![];
+        let mut cursor = None;
+        loop {
+            let (result, next) = db.clear_expired(cursor, 7).wait().unwrap();
+            batches.push(result.cleared);
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(batches, vec![7, 7, 6]);
+
+        let (begin, end) = Subspace::from("E").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+        let (begin, end) = Subspace::from("B").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+
+        // Nothing left to clear
+        assert_eq!(
+            db.clear_expired(None, 7).wait().unwrap(),
+            (ClearedExpired::default(), None)
+        );
+    }
+
+    #[test]
+    fn legal_hold_test() {
+        let db = MemoryDB::new(1);
+        let n = 10;
+
+        for i in 0..n {
+            let message = make_message(&format!("hash {}", i), "sender", "held", vec![1]);
+            db.insert_message(message).wait().unwrap();
+        }
+        let hold = proto::LegalHold {
+            client_id: "held".into(),
+            reason: "case 1".into(),
+            placed_at: None,
+        };
+        db.add_legal_hold(hold.clone()).wait().unwrap();
+        assert_eq!(db.list_legal_holds().wait().unwrap(), vec![hold]);
+
+        // Held entries are skipped, in batches small enough that some are
+        // made up of held entries alone
+        let mut cleared = 0;
+        let mut held = 0;
+        let mut cursor = None;
+        loop {
+            let (result, next) = db.clear_expired(cursor, 3).wait().unwrap();
+            cleared += result.cleared;
+            held += result.held.get("held").cloned().unwrap_or(0);
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!((cleared, held), (n, n));
+
+        // The held client keeps its entries and the bodies they reference
+        let (begin, end) = Subspace::from(("M", "sender")).range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+        let (begin, end) = Subspace::from(("M", "held")).range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), n);
+        let (begin, end) = Subspace::from("B").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), n);
+
+        let result = db.delete_message("held", b"hash 0".to_vec()).wait();
+        match result {
+            Err(StorageError::LegalHold { client_id }) => assert_eq!(client_id, "held"),
+            other => panic!("expected a legal hold error, got {:?}", other),
+        }
+
+        // Once the hold is lifted, expiry and deletes go ahead
+        assert_eq!(db.remove_legal_hold("held").wait().unwrap(), true);
+        assert_eq!(db.remove_legal_hold("held").wait().unwrap(), false);
+        assert_eq!(
+            db.delete_message("held", b"hash 0".to_vec())
+                .wait()
+                .unwrap()
+                .is_some(),
+            true
+        );
+        let (result, _cursor) = db.clear_expired(None, 100).wait().unwrap();
+        assert_eq!(result.cleared, n - 1);
+        let (begin, end) = Subspace::from("B").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+    }
+
+    #[test]
+    fn retention_test() {
+        let mut client_days = HashMap::new();
+        client_days.insert("premium".to_string(), 36_500);
+        let db = MemoryDB::new(1).with_retention(Retention::new(1).with_client_days(client_days));
+
+        let basic = make_message("hash 1", "sender", "basic", vec![1]);
+        let premium = make_message("hash 2", "sender", "premium", vec![2]);
+        let short_ttl = proto::Message {
+            ttl_seconds: 60,
+            ..make_message("hash 3", "sender", "premium", vec![3])
+        };
+        let long_ttl = proto::Message {
+            ttl_seconds: 3600,
+            ..make_message("hash 4", "sender", "premium", vec![4]).timestamped()
+        };
+        for message in &[&basic, &premium, &short_ttl, &long_ttl] {
+            db.insert_message((*message).clone()).wait().unwrap();
+        }
+
+        // Reads honor each message's policy, before and after clearing
+        for _ in 0..2 {
+            let result = db
+                .get_messages_for("basic", None, Box::new(|_| true))
+                .wait()
+                .unwrap()
+                .messages;
+            assert_eq!(result, vec![]);
+            let result = db
+                .get_messages_for("premium", None, Box::new(|_| true))
+                .wait()
+                .unwrap()
+                .messages;
+            assert_eq!(result, vec![premium.clone(), long_ttl.clone()]);
+
+            db.clear_expired(None, 100).wait().unwrap();
+        }
+
+        // The sender keeps its entries for as long as the recipient does
+        let (begin, end) = Subspace::from(("M", "sender")).range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 2);
+        let (begin, end) = Subspace::from("E").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 4);
+    }
+
+    #[test]
+    fn purge_client_test() {
+        use prost::Message;
+
+        let db = MemoryDB::new(1);
+
+        let purged = vec![
+            make_message("hash 1", "alice", "bob", vec![1]),
+            make_message("hash 2", "alice", "bob", vec![0u8; 40000]),
+            make_message("hash 3", "bob", "alice", vec![3]),
+            make_message("hash 4", "alice", "alice", vec![4]),
+        ];
+        for message in &purged {
+            db.insert_message(message.clone()).wait().unwrap();
+        }
+        let legacy = make_message("hash 5", "alice", "dave", vec![5]);
+        insert_legacy_message(&db, legacy.clone());
+        let held = make_message("hash 6", "carol", "alice", vec![6]);
+        let unrelated = make_message("hash 7", "bob", "carol", vec![7]);
+        for message in &[&held, &unrelated] {
+            db.insert_message((*message).clone()).wait().unwrap();
+        }
+        db.add_legal_hold(proto::LegalHold {
+            client_id: "carol".into(),
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+
+        let mut total = PurgedMessages::default();
+        loop {
+            let (result, more) = db.purge_client("alice", 2).wait().unwrap();
+            total.messages += result.messages;
+            total.bytes += result.bytes;
+            total.held += result.held;
+            if !more {
+                break;
+            }
+        }
+        let bytes = purged
+            .iter()
+            .chain(Some(&legacy))
+            .map(|message| message.encoded_len())
+            .sum();
+        assert_eq!(
+            total,
+            PurgedMessages {
+                messages: 5,
+                bytes,
+                held: 1,
+            }
+        );
+
+        // Nothing of alice's is left, and the held counterpart keeps its copy
+        for subspace in &["M", "T"] {
+            let (begin, end) = Subspace::from((*subspace, "alice")).range();
+            assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+        }
+        for (client_id, count) in &[("bob", 1), ("carol", 2), ("dave", 0)] {
+            let (begin, end) = Subspace::from(("M", *client_id)).range();
+            assert_eq!(scan_keys(&db, &begin, &end).len(), *count);
+        }
+        let (begin, end) = Subspace::from("B").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 2);
+        let (begin, end) = Subspace::from("E").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 3);
+        let (begin, end) = Subspace::from("R").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+
+        // A client under legal hold can't be purged
+        assert_eq!(db.purge_client("carol", 2).wait().is_err(), true);
+    }
+
+    #[test]
+    fn shared_body_test() {
+        let db = MemoryDB::new(1);
+
+        let message = make_message("hash", "from id", "to id", vec![0u8; 40000]).timestamped();
+        db.insert_message(message.clone()).wait().unwrap();
+
+        // One copy of the body, and a single reference key in each mailbox
+        let (begin, end) = body_range(b"hash");
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 5);
+        for client_id in &["from id", "to id"] {
+            let (begin, end) = Subspace::from(("M", *client_id)).range();
+            assert_eq!(
+                scan_keys(&db, &begin, &end),
+                vec![mailbox_key(client_id, b"hash")]
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_insert_test() {
+        let db = MemoryDB::new(1);
+
+        let message = make_message("hash", "from id", "to id", vec![1, 2, 3]).timestamped();
+        let inserted = db.insert_message(message.clone()).wait().unwrap();
+        assert_eq!(inserted.duplicate, false);
+
+        // A retry with a new timestamp gets the original back
+        let mut retry = message.clone();
+        retry.received_at = Some(proto::Timestamp {
+            seconds: 1,
+            nanos: 2,
+        });
+        let inserted = db.insert_message(retry).wait().unwrap();
+        assert_eq!(inserted.duplicate, true);
+        assert_eq!(inserted.message, message);
+
+        // Nothing is indexed under the retry's timestamp
+        let (begin, end) = Subspace::from(("T", "to id")).range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 1);
+        let result = db
+            .get_messages_for("to id", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result, vec![message]);
+    }
+
+    #[test]
+    fn insert_messages_test() {
+        let db = MemoryDB::new(1);
+
+        let first = make_message("hash 1", "from id", "to id", vec![1, 2, 3]).timestamped();
+        db.insert_message(first.clone()).wait().unwrap();
+
+        let forged = make_message("hash 1", "someone else", "to id", vec![4]).timestamped();
+        let second = make_message("hash 2", "from id", "to id", vec![5]).timestamped();
+        let results = db
+            .insert_messages(vec![first.clone(), forged, second.clone(), second.clone()])
+            .wait()
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap().duplicate, true);
+        match results[1] {
+            Err(StorageError::HashConflict) => (),
+            ref other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!(results[2].as_ref().unwrap().duplicate, false);
+        // Later messages in a batch see the earlier ones
+        assert_eq!(results[3].as_ref().unwrap().duplicate, true);
+
+        let result = db
+            .get_messages_for("to id", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result, vec![first, second]);
+    }
+
+    #[test]
+    fn hash_conflict_test() {
+        let db = MemoryDB::new(1);
+
+        let message = make_message("hash", "from id", "to id", vec![1, 2, 3]).timestamped();
+        db.insert_message(message.clone()).wait().unwrap();
+
+        let forged = make_message("hash", "someone else", "to id", vec![4, 5, 6]).timestamped();
+        assert_eq!(db.insert_message(forged).wait().is_err(), true);
+
+        let result = db
+            .get_messages_for("to id", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result, vec![message]);
+    }
+
+    #[test]
+    fn legacy_message_test() {
+        let db = MemoryDB::new(1);
+
+        let legacy = make_message("legacy", "from id", "to id", vec![0u8; 40000]).timestamped();
+        insert_legacy_message(&db, legacy.clone());
+        let shared = make_message("shared", "from id", "to id", vec![1, 2, 3]).timestamped();
+        db.insert_message(shared.clone()).wait().unwrap();
+
+        // Both layouts can be read side by side
+        let result = db
+            .get_messages_for("to id", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result, vec![legacy.clone(), shared.clone()]);
+
+        // Migrate one entry at a time
+        let mut cursor = None;
+        let mut migrated = 0;
+        loop {
+            let (count, next) = db.migrate_legacy_blobs(cursor, 1).wait().unwrap();
+            migrated += count;
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(migrated, 2);
+
+        for client_id in &["from id", "to id"] {
+            let (begin, end) = Subspace::from(("M", *client_id)).range();
+            assert_eq!(
+                scan_keys(&db, &begin, &end),
+                vec![
+                    mailbox_key(client_id, b"legacy"),
+                    mailbox_key(client_id, b"shared")
+                ]
+            );
+
+            let result = db
+                .get_messages_for(client_id, None, Box::new(|_| true))
+                .wait()
+                .unwrap()
+                .messages;
+            assert_eq!(result, vec![legacy.clone(), shared.clone()]);
+        }
+        let (begin, end) = body_range(b"legacy");
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 5);
+
+        let deleted = db
+            .delete_message("from id", b"legacy".to_vec())
+            .wait()
+            .unwrap();
+        assert_eq!(deleted, Some(legacy));
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+    }
+
+    #[test]
+    fn compression_test() {
+        let db = MemoryDB::new(1).with_encoding(BlobEncoding {
+            codec: Codec::Zstd,
+            keyring: None,
+        });
+
+        // Written before compression was switched on
+        let plain = make_message("plain", "from id", "to id", vec![0u8; 40000]).timestamped();
+        insert_legacy_message(&db, plain.clone());
+        let compressed =
+            make_message("compressed", "from id", "to id", vec![0u8; 40000]).timestamped();
+        db.insert_message(compressed.clone()).wait().unwrap();
+
+        // The compressed body fits in a single chunk
+        let (begin, end) = body_range(b"compressed");
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 1);
+
+        let result = db
+            .get_messages_for("to id", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result, vec![compressed.clone(), plain]);
+
+        let deleted = db
+            .delete_message("from id", b"compressed".to_vec())
+            .wait()
+            .unwrap();
+        assert_eq!(deleted, Some(compressed));
+    }
+
+    fn make_encoding(active_key: &str) -> BlobEncoding {
+        let mut keys = HashMap::new();
+        keys.insert("old".to_string(), vec![1u8; 32]);
+        keys.insert("new".to_string(), vec![2u8; 32]);
+        BlobEncoding {
+            codec: Codec::None,
+            keyring: Some(Arc::new(Keyring::new(active_key, keys).unwrap())),
+        }
+    }
+
+    /// The key IDs recorded in the chunks of the body of `hash`.
+    fn body_key_ids(db: &MemoryDB, hash: &[u8]) -> Vec<String> {
+        use prost::Message;
+
+        let (begin, end) = body_range(hash);
+        db.transact(move |trx| {
+            let mut key_ids = vec![];
+            for value in scan_values(trx, &begin, &end)? {
+                key_ids.push(proto::BlobValue::decode(&value)?.key_id);
+            }
+            Ok(key_ids)
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn encryption_test() {
+        let db = MemoryDB::new(1).with_encoding(make_encoding("old"));
+
+        let message = make_message("hash", "from id", "to id", vec![1, 2, 3]).timestamped();
+        db.insert_message(message.clone()).wait().unwrap();
+        assert_eq!(body_key_ids(&db, b"hash"), vec!["old".to_string()]);
+
+        // The envelope isn't readable in the stored chunks
+        let (begin, end) = body_range(b"hash");
+        let values = db
+            .transact(move |trx| scan_values(trx, &begin, &end))
+            .wait()
+            .unwrap();
+        assert_eq!(values[0].windows(5).any(|w| w == b"to id"), false);
+
+        // Rotate to the new key
+        let encoding = make_encoding("new");
+        let rewritten = db
+            .transact(move |trx| reencrypt_blobs(trx, None, 10, &encoding))
+            .wait()
+            .unwrap();
+        assert_eq!(rewritten, (1, None));
+        assert_eq!(body_key_ids(&db, b"hash"), vec!["new".to_string()]);
+
+        let result = db
+            .get_messages_for("to id", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(result, vec![message]);
+    }
+
+    #[test]
+    fn expired_reference_test() {
+        let db = MemoryDB::new(1);
+
+        let message = make_message("hash", "from id", "to id", vec![1, 2, 3]);
+        db.insert_message(message.clone()).wait().unwrap();
+
+        // Keep the sender's entry from expiring
+        let expires = to_integer_date(Retention::new(1).expires_at(&message).date());
+        let key = ("E", expires, "from id", b"hash".to_vec()).to_vec();
+        db.transact(move |trx| {
+            trx.clear(&key);
+            Ok(())
+        })
+        .wait()
+        .unwrap();
+
+        // The body stays while the sender still references it
+        db.clear_expired(None, 100).wait().unwrap();
+        let (begin, end) = Subspace::from(("M", "to id")).range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+        let (begin, end) = body_range(b"hash");
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 1);
+    }
+
+    /// Check everything in small batches, counting problems by kind.
+    fn check_all(db: &MemoryDB, repair: bool) -> Vec<(ProblemKind, usize, usize)> {
+        use std::collections::BTreeMap;
+
+        let mut found = BTreeMap::new();
+        let mut cursor = None;
+        loop {
+            let (report, next) = db.check_integrity(cursor, 3, repair).wait().unwrap();
+            for problem in report.problems {
+                let counts = found.entry(problem.kind).or_insert((0, 0));
+                counts.0 += 1;
+                if problem.repaired {
+                    counts.1 += 1;
+                }
+            }
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        found
+            .into_iter()
+            .map(|(kind, (count, repaired))| (kind, count, repaired))
+            .collect()
+    }
+
+    #[test]
+    fn check_integrity_test() {
+        let db = MemoryDB::new(1);
+        for hash in &["good", "truncated", "dangling", "unpaired", "mismatched"] {
+            let message = make_message(hash, "alice", "bob", vec![0u8; 40000]).timestamped();
+            db.insert_message(message).wait().unwrap();
+        }
+        assert_eq!(check_all(&db, false), vec![]);
+
+        db.transact(|trx| {
+            // Lose the last chunk of one body, and all of another
+            let (begin, end) = body_range(b"truncated");
+            let mut last = None;
+            trx.scan(&begin, &end, &mut |key, _value| {
+                last = Some(key.to_vec());
+                true
+            })?;
+            trx.clear(&last.unwrap());
+            let (begin, end) = body_range(b"dangling");
+            trx.clear_range(&begin, &end);
+            // Lose one participant's entry, and swap another's counterpart
+            trx.clear(&mailbox_key("bob", b"unpaired"));
+            trx.set(&mailbox_key("alice", b"mismatched"), &"mallory".to_vec());
+            Ok(())
+        })
+        .wait()
+        .unwrap();
+
+        assert_eq!(
+            check_all(&db, false),
+            vec![
+                (ProblemKind::IncompleteBlob, 1, 0),
+                (ProblemKind::UnpairedEntry, 1, 0),
+                (ProblemKind::DanglingEntry, 2, 0),
+                (ProblemKind::MismatchedEntry, 1, 0),
+                (ProblemKind::OrphanedIndexEntry, 1, 0),
+                (ProblemKind::OrphanedExpiryKey, 1, 0),
+            ]
+        );
+
+        // Removing the truncated body leaves its entries dangling, and
+        // removing those orphans their index entries and expiry keys, all
+        // within the one pass
+        assert_eq!(
+            check_all(&db, true),
+            vec![
+                (ProblemKind::IncompleteBlob, 1, 1),
+                (ProblemKind::UnpairedEntry, 1, 0),
+                (ProblemKind::DanglingEntry, 4, 4),
+                (ProblemKind::MismatchedEntry, 1, 1),
+                (ProblemKind::OrphanedIndexEntry, 5, 5),
+                (ProblemKind::OrphanedExpiryKey, 5, 5),
+            ]
+        );
+        assert_eq!(
+            check_all(&db, false),
+            vec![(ProblemKind::UnpairedEntry, 1, 0)]
+        );
+
+        let messages = db
+            .get_messages_for("alice", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        let hashes: Vec<&[u8]> = messages.iter().map(|m| &m.hash[..]).collect();
+        assert_eq!(hashes, vec![&b"good"[..], b"mismatched", b"unpaired"]);
+    }
+
+    #[test]
+    fn migrate_test() {
+        use crate::storage::{migrate, SCHEMA_VERSION};
+
+        let db = MemoryDB::new(30);
+        for n in 0..5 {
+            let message = make_message(&format!("legacy {}", n), "alice", "bob", vec![0u8; 40000])
+                .timestamped();
+            insert_legacy_message(&db, message);
+        }
+        let message = make_message("current", "alice", "bob", vec![0u8; 100]).timestamped();
+        db.insert_message(message).wait().unwrap();
+        assert_eq!(db.schema_version().wait().unwrap(), 0);
+
+        // Migrations only run from the version before them
+        assert_eq!(db.run_migration(2, None, 100).wait().is_err(), true);
+
+        assert_eq!(migrate(&db, 3).unwrap(), SCHEMA_VERSION);
+        assert_eq!(db.schema_version().wait().unwrap(), SCHEMA_VERSION);
+
+        // Legacy entries now reference shared bodies, and their expiry keys
+        // are filed alongside the current ones
+        let (begin, end) = Subspace::from("R").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 0);
+        let (begin, end) = Subspace::from("E").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 12);
+        let (begin, end) = Subspace::from("B").range();
+        assert_eq!(scan_keys(&db, &begin, &end).len(), 5 * 5 + 1);
+        let messages = db
+            .get_messages_for("bob", None, Box::new(|_| true))
+            .wait()
+            .unwrap()
+            .messages;
+        assert_eq!(messages.len(), 6);
+
+        // Running again has nothing to do
+        assert_eq!(migrate(&db, 3).unwrap(), SCHEMA_VERSION);
+
+        // A newer schema than this build knows about is refused
+        db.transact(|trx| {
+            set_schema_version(trx, SCHEMA_VERSION + 1);
+            Ok(())
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(migrate(&db, 3).is_err(), true);
+    }
+}
@@ -0,0 +1,59 @@
+// An in-memory key-value store for `LocalDB`. Nothing is persisted, which
+// makes this backend suitable for tests and local development only.
+use super::local::{KeyValues, LocalDB, Write, WriteBatch};
+use super::StorageError;
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+#[derive(Default)]
+pub struct MemoryKeyValues {
+    kvs: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+pub type MemoryDB = LocalDB<MemoryKeyValues>;
+
+impl LocalDB<MemoryKeyValues> {
+    pub fn new(expiry_days: i64) -> Self {
+        LocalDB::with_key_values(MemoryKeyValues::default(), expiry_days)
+    }
+}
+
+impl KeyValues for MemoryKeyValues {
+    fn scan(
+        &self,
+        begin: &[u8],
+        end: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), StorageError> {
+        let kvs = self.kvs.read().unwrap();
+        for (key, value) in kvs.range(begin.to_vec()..end.to_vec()) {
+            if !f(key, value) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), StorageError> {
+        let mut kvs = self.kvs.write().unwrap();
+        for write in batch.into_writes() {
+            match write {
+                Write::Set(key, value) => {
+                    kvs.insert(key, value);
+                }
+                Write::Clear(key) => {
+                    kvs.remove(&key);
+                }
+                Write::ClearRange(begin, end) => {
+                    let keys: Vec<Vec<u8>> =
+                        kvs.range(begin..end).map(|(key, _)| key.clone()).collect();
+                    for key in keys {
+                        kvs.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
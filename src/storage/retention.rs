@@ -0,0 +1,106 @@
+// How long messages are kept. Each recipient gets the default retention
+// period unless the config overrides it for their client ID, and a message
+// can ask to be dropped sooner with its `ttl_seconds`. A message is kept for
+// its recipient's period in both participants' mailboxes. Policy changes
+// apply to reads straight away, but messages already stored are still cleared
+// on the date worked out when they were received.
+use super::received_at;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use switchroom_grpc::proto;
+
+#[derive(Clone, Debug)]
+pub struct Retention {
+    default_days: i64,
+    client_days: Arc<HashMap<String, i64>>,
+}
+
+impl Retention {
+    pub fn new(default_days: i64) -> Self {
+        Retention {
+            default_days,
+            client_days: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Keep messages received by the clients in `client_days` for the given
+    /// number of days, instead of the default.
+    pub fn with_client_days(mut self, client_days: HashMap<String, i64>) -> Self {
+        self.client_days = Arc::new(client_days);
+        self
+    }
+
+    /// The retention period for messages which predate per-recipient
+    /// policies.
+    pub fn default_days(&self) -> i64 {
+        self.default_days
+    }
+
+    /// Days to keep messages received by `client_id`.
+    pub fn days_for(&self, client_id: &str) -> i64 {
+        self.client_days
+            .get(client_id)
+            .cloned()
+            .unwrap_or(self.default_days)
+    }
+
+    /// The time after which `message` is no longer kept.
+    pub fn expires_at(&self, message: &proto::Message) -> chrono::DateTime<chrono::Utc> {
+        let mut kept = chrono::Duration::days(self.days_for(&message.to));
+        // Clients can send any TTL, but it can only shorten the retention
+        // period, so it's clamped before it's used as a duration
+        if message.ttl_seconds > 0 && message.ttl_seconds < kept.num_seconds() {
+            kept = chrono::Duration::seconds(message.ttl_seconds);
+        }
+        received_at(message)
+            .checked_add_signed(kept)
+            .unwrap_or_else(|| chrono::MAX_DATE.and_hms(0, 0, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::storage::testing;
+
+    fn make_message(to: &str, ttl_seconds: i64) -> proto::Message {
+        proto::Message {
+            ttl_seconds,
+            ..testing::make_message("hash", "from id", to, vec![])
+        }
+    }
+
+    #[test]
+    fn expires_at_test() {
+        let mut client_days = HashMap::new();
+        client_days.insert("premium".to_string(), 365);
+        let retention = Retention::new(30).with_client_days(client_days);
+
+        let message = make_message("basic", 0);
+        let received = received_at(&message);
+        assert_eq!(
+            retention.expires_at(&message),
+            received + chrono::Duration::days(30)
+        );
+        assert_eq!(
+            retention.expires_at(&make_message("premium", 0)),
+            received + chrono::Duration::days(365)
+        );
+
+        // A TTL can shorten the recipient's retention, but not extend it
+        assert_eq!(
+            retention.expires_at(&make_message("premium", 60)),
+            received + chrono::Duration::seconds(60)
+        );
+        assert_eq!(
+            retention.expires_at(&make_message("basic", 86_400 * 100)),
+            received + chrono::Duration::days(30)
+        );
+        assert_eq!(
+            retention.expires_at(&make_message("premium", i64::max_value())),
+            received + chrono::Duration::days(365)
+        );
+    }
+}
@@ -0,0 +1,279 @@
+// Checks sent messages before they're stored. Anything the storage layer
+// would happily write but no client could make sense of is rejected here,
+// along with messages over the configured size limits.
+use crate::config;
+use crate::messages::get_timestamp;
+use crate::metrics;
+
+use switchroom_grpc::proto;
+
+// Length of a message hash, which is a blake2b-256 digest
+pub const HASH_LENGTH: usize = 32;
+// Longest client ID accepted, in bytes
+pub const MAX_CLIENT_ID_LENGTH: usize = 256;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum ValidationError {
+    #[fail(display = "hash must be {} bytes, got {}", expected, len)]
+    InvalidHash { expected: usize, len: usize },
+    #[fail(display = "{} client ID is empty", field)]
+    MissingClientId { field: &'static str },
+    #[fail(display = "{} client ID is invalid: {}", field, err)]
+    InvalidClientId { field: &'static str, err: String },
+    #[fail(display = "body is {} bytes, over the limit of {}", len, limit)]
+    BodyTooLarge { len: usize, limit: usize },
+    #[fail(display = "nonce is {} bytes, over the limit of {}", len, limit)]
+    NonceTooLarge { len: usize, limit: usize },
+    #[fail(display = "sent_at is missing")]
+    MissingSentAt,
+    #[fail(display = "sent_at is invalid: {}", err)]
+    InvalidSentAt { err: String },
+    #[fail(display = "value_cents is negative: {}", value_cents)]
+    NegativeValue { value_cents: i32 },
+    #[fail(display = "ttl_seconds is invalid: {}", err)]
+    InvalidTtl { err: String },
+}
+
+impl ValidationError {
+    /// The label the rejection is counted under.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ValidationError::InvalidHash { .. } => "invalid_hash",
+            ValidationError::MissingClientId { .. } => "missing_client_id",
+            ValidationError::InvalidClientId { .. } => "invalid_client_id",
+            ValidationError::BodyTooLarge { .. } => "body_too_large",
+            ValidationError::NonceTooLarge { .. } => "nonce_too_large",
+            ValidationError::MissingSentAt => "missing_sent_at",
+            ValidationError::InvalidSentAt { .. } => "invalid_sent_at",
+            ValidationError::NegativeValue { .. } => "negative_value",
+            ValidationError::InvalidTtl { .. } => "invalid_ttl",
+        }
+    }
+}
+
+/// Checks a sent message, counting it in the metrics if it's rejected.
+pub fn check_message(
+    message: &proto::Message,
+    limits: &config::Limits,
+) -> Result<(), ValidationError> {
+    validate_message(message, limits, &get_timestamp()).map_err(|err| {
+        metrics::INVALID_MESSAGE
+            .with_label_values(&[err.reason()])
+            .inc();
+        err
+    })
+}
+
+/// Checks a sent message against the limits, as of `now`.
+pub fn validate_message(
+    message: &proto::Message,
+    limits: &config::Limits,
+    now: &proto::Timestamp,
+) -> Result<(), ValidationError> {
+    validate_hash(&message.hash)?;
+    validate_client_id("from", &message.from)?;
+    validate_client_id("to", &message.to)?;
+    if message.body.len() > limits.max_body_bytes {
+        return Err(ValidationError::BodyTooLarge {
+            len: message.body.len(),
+            limit: limits.max_body_bytes,
+        });
+    }
+    if message.nonce.len() > limits.max_nonce_bytes {
+        return Err(ValidationError::NonceTooLarge {
+            len: message.nonce.len(),
+            limit: limits.max_nonce_bytes,
+        });
+    }
+    validate_sent_at(message.sent_at.as_ref(), limits, now)?;
+    if message.value_cents < 0 {
+        return Err(ValidationError::NegativeValue {
+            value_cents: message.value_cents,
+        });
+    }
+    validate_ttl(message.ttl_seconds, limits)?;
+    Ok(())
+}
+
+/// Checks a message hash, such as one a client acks.
+pub fn validate_hash(hash: &[u8]) -> Result<(), ValidationError> {
+    if hash.len() != HASH_LENGTH {
+        return Err(ValidationError::InvalidHash {
+            expected: HASH_LENGTH,
+            len: hash.len(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_client_id(field: &'static str, client_id: &str) -> Result<(), ValidationError> {
+    if client_id.is_empty() {
+        return Err(ValidationError::MissingClientId { field });
+    }
+    if client_id.len() > MAX_CLIENT_ID_LENGTH {
+        return Err(ValidationError::InvalidClientId {
+            field,
+            err: format!(
+                "{} bytes, over the limit of {}",
+                client_id.len(),
+                MAX_CLIENT_ID_LENGTH
+            ),
+        });
+    }
+    if client_id.trim() != client_id {
+        return Err(ValidationError::InvalidClientId {
+            field,
+            err: "leading or trailing whitespace".to_string(),
+        });
+    }
+    if let Some(c) = client_id.chars().find(|c| c.is_control()) {
+        return Err(ValidationError::InvalidClientId {
+            field,
+            err: format!("contains control character {:?}", c),
+        });
+    }
+    Ok(())
+}
+
+fn validate_sent_at(
+    sent_at: Option<&proto::Timestamp>,
+    limits: &config::Limits,
+    now: &proto::Timestamp,
+) -> Result<(), ValidationError> {
+    let sent_at = match sent_at {
+        Some(sent_at) => sent_at,
+        None => return Err(ValidationError::MissingSentAt),
+    };
+    if sent_at.nanos < 0 || sent_at.nanos >= 1_000_000_000 {
+        return Err(ValidationError::InvalidSentAt {
+            err: format!("nanos out of range: {}", sent_at.nanos),
+        });
+    }
+    if sent_at.seconds <= 0 {
+        return Err(ValidationError::InvalidSentAt {
+            err: format!("not after the epoch: {}", sent_at.seconds),
+        });
+    }
+    // Allow for clients whose clocks run a little fast
+    let ahead = sent_at.seconds.saturating_sub(now.seconds);
+    if ahead > limits.max_clock_skew_seconds {
+        return Err(ValidationError::InvalidSentAt {
+            err: format!(
+                "{} seconds in the future, over the limit of {}",
+                ahead, limits.max_clock_skew_seconds
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn validate_ttl(ttl_seconds: i64, limits: &config::Limits) -> Result<(), ValidationError> {
+    if ttl_seconds < 0 {
+        return Err(ValidationError::InvalidTtl {
+            err: format!("negative: {}", ttl_seconds),
+        });
+    }
+    if ttl_seconds > limits.max_ttl_seconds {
+        return Err(ValidationError::InvalidTtl {
+            err: format!(
+                "{} seconds, over the limit of {}",
+                ttl_seconds, limits.max_ttl_seconds
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::storage::testing;
+
+    const NOW: proto::Timestamp = proto::Timestamp {
+        seconds: 1_500_000_000,
+        nanos: 0,
+    };
+
+    fn make_message() -> proto::Message {
+        proto::Message {
+            hash: vec![1; HASH_LENGTH],
+            sent_at: Some(proto::Timestamp {
+                seconds: NOW.seconds - 60,
+                nanos: 2,
+            }),
+            value_cents: 100,
+            ..testing::make_message("", "alice", "bob", "yoyoyoyo".into())
+        }
+    }
+
+    fn validate(message: &proto::Message) -> Result<(), ValidationError> {
+        validate_message(message, &config::Limits::default(), &NOW)
+    }
+
+    #[test]
+    fn valid_message_test() {
+        assert_eq!(validate(&make_message()), Ok(()));
+    }
+
+    #[test]
+    fn invalid_message_test() {
+        let limits = config::Limits::default();
+        let cases: &[(fn(&mut proto::Message), &str)] = &[
+            (|m| m.hash = vec![1; 31], "invalid_hash"),
+            (|m| m.hash.clear(), "invalid_hash"),
+            (|m| m.to.clear(), "missing_client_id"),
+            (|m| m.from = " alice".into(), "invalid_client_id"),
+            (|m| m.from = "al\nice".into(), "invalid_client_id"),
+            (
+                |m| m.to = "b".repeat(MAX_CLIENT_ID_LENGTH + 1),
+                "invalid_client_id",
+            ),
+            (|m| m.nonce = vec![0; 25], "nonce_too_large"),
+            (|m| m.sent_at = None, "missing_sent_at"),
+            (
+                |m| m.sent_at.as_mut().unwrap().nanos = -1,
+                "invalid_sent_at",
+            ),
+            (
+                |m| m.sent_at.as_mut().unwrap().seconds = 0,
+                "invalid_sent_at",
+            ),
+            (
+                |m| m.sent_at.as_mut().unwrap().seconds = NOW.seconds + 3600,
+                "invalid_sent_at",
+            ),
+            (|m| m.value_cents = -1, "negative_value"),
+            (|m| m.ttl_seconds = -1, "invalid_ttl"),
+            (|m| m.ttl_seconds = i64::max_value(), "invalid_ttl"),
+        ];
+        for &(change, reason) in cases {
+            let mut message = make_message();
+            change(&mut message);
+            assert_eq!(validate(&message).unwrap_err().reason(), reason);
+        }
+
+        let mut message = make_message();
+        message.body = vec![0; limits.max_body_bytes + 1];
+        assert_eq!(
+            validate(&message),
+            Err(ValidationError::BodyTooLarge {
+                len: limits.max_body_bytes + 1,
+                limit: limits.max_body_bytes,
+            })
+        );
+
+        // Small differences in clocks are tolerated
+        let mut message = make_message();
+        message.sent_at = Some(proto::Timestamp {
+            seconds: NOW.seconds + limits.max_clock_skew_seconds,
+            nanos: 0,
+        });
+        assert_eq!(validate(&message), Ok(()));
+
+        // As is a TTL of exactly the limit
+        let mut message = make_message();
+        message.ttl_seconds = limits.max_ttl_seconds;
+        assert_eq!(validate(&message), Ok(()));
+    }
+}